@@ -1,8 +1,11 @@
 use indicatif::style::TemplateError;
 use indicatif::{ProgressBar, ProgressStyle};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::time::Duration;
 
+use crate::config::ObjectState;
+
 #[derive(Clone, Debug, clap::Parser)]
 pub struct Args {
     #[clap(subcommand)]
@@ -13,6 +16,17 @@ pub struct Args {
 pub enum Subcommands {
     /// Applies a given service plan
     Apply(Apply),
+    /// Shows how each network reference of a service plan resolves
+    Networks(Networks),
+    /// Shows who may talk to whom on which ports
+    Firewall(Firewall),
+    /// Exports the objects of a service plan and their relations as a graph
+    Graph(Graph),
+    /// Checks that the firewall imports and exports of a service plan line up
+    Lint(Lint),
+    /// Moves the VMs of a whole environment or of a single service to another state
+    #[command(subcommand)]
+    State(StateCommand),
     // /// Deletes all objects by hostname created in this plan
     // Delete(Delete)
     // /// Dumps the diff of the current state and the state currently applied
@@ -20,8 +34,7 @@ pub enum Subcommands {
 }
 
 #[derive(Clone, Debug, clap::Args)]
-#[command(name = "service-plan")]
-pub struct Apply {
+pub struct PlanTarget {
     #[arg(help = "The path to the plan's YAML file")]
     pub plan: PathBuf,
     #[arg(help = "The project in which the plan is applied")]
@@ -30,6 +43,114 @@ pub struct Apply {
     pub subproject: String,
     #[arg(help = "The environment on which the plan should be applied")]
     pub environment: String,
+    #[arg(
+        long,
+        help = "A YAML file adding or overriding resource kinds, the plan's own kinds take precedence"
+    )]
+    pub kinds: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "A YAML file of size profiles, the plan's own profiles take precedence"
+    )]
+    pub sizes: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+#[command(name = "service-plan")]
+pub struct Apply {
+    #[command(flatten)]
+    pub target: PlanTarget,
+    #[arg(
+        long,
+        default_value_t = 80,
+        help = "Warn when a network's utilization exceeds this percentage after planning"
+    )]
+    pub warn_utilization: u8,
+    #[arg(
+        long,
+        help = "A lock file, which is held while planning and applying to prevent concurrent IP allocations"
+    )]
+    pub lock_file: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Take over existing objects, which are not owned by this plan"
+    )]
+    pub adopt: bool,
+    #[arg(long, help = "Apply even if the firewall lint reports errors")]
+    pub ignore_lint: bool,
+    #[arg(
+        long,
+        help = "Roll changes of existing VMs out in batches of this many VMs"
+    )]
+    pub batch_size: Option<NonZeroUsize>,
+    #[arg(long, help = "Roll changes of existing VMs out one zone at a time")]
+    pub by_zone: bool,
+    #[arg(
+        long,
+        help = "Wait this many seconds between batches instead of asking for confirmation"
+    )]
+    pub pause: Option<u64>,
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+pub enum StateCommand {
+    /// Sets the Serveradmin state of the VMs, after checking the transitions are allowed
+    Set(StateSet),
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct StateSet {
+    #[command(flatten)]
+    pub target: PlanTarget,
+    #[arg(value_enum, help = "The state to move the VMs to")]
+    pub state: ObjectState,
+    #[arg(
+        long,
+        help = "Only move the VMs of this service instead of the whole environment"
+    )]
+    pub service: Option<String>,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Networks {
+    #[command(flatten)]
+    pub target: PlanTarget,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Firewall {
+    #[command(flatten)]
+    pub target: PlanTarget,
+    #[arg(long, value_enum, default_value_t = FirewallFormat::Table)]
+    pub format: FirewallFormat,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum FirewallFormat {
+    Table,
+    Dot,
+    Mermaid,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Lint {
+    #[command(flatten)]
+    pub target: PlanTarget,
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct Graph {
+    #[command(flatten)]
+    pub target: PlanTarget,
+    #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+    pub format: GraphFormat,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
 }
 
 pub fn show_spinner(message: &str) -> anyhow::Result<impl FnOnce()> {
@@ -1,10 +1,136 @@
 use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
 
-use crate::processable_value::ProcessableValue;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{EnumAccess, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use ipnet::IpNet;
+
+use crate::port_spec::PortSpec;
+use crate::processable_value::{ProcessableValue, ProcessableValueVisitor};
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ServicePlan {
     pub services: HashMap<String, Service>,
+    /// Always on, marking the objects with the name of the plan file unless configured otherwise
+    #[serde(default)]
+    pub ownership: PlanOwnership,
+    /// Resource kinds added or overridden by this plan
+    #[serde(default)]
+    pub kinds: HashMap<String, ResourceKind>,
+    /// Size profiles added or overridden by this plan
+    #[serde(default)]
+    pub sizes: HashMap<String, SizeProfile>,
+    /// Shell commands run around the apply
+    #[serde(default)]
+    pub hooks: PlanHooks,
+}
+
+/// Shell commands, which get the affected objects as JSON on stdin and the target in
+/// `SERVICE_PLAN_*` variables
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlanHooks {
+    /// Run before anything is committed, a failing command aborts the apply
+    #[serde(default)]
+    pub pre_apply: Vec<String>,
+    /// Run once all objects are committed, or with the committed batches when a rollout stops
+    #[serde(default)]
+    pub post_apply: Vec<String>,
+    /// Run for every object created by the apply, which is also given in `SERVICE_PLAN_HOSTNAME`
+    #[serde(default)]
+    pub post_object_create: Vec<String>,
+}
+
+/// The resources of a VM, referenced by name as `size`
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SizeProfile {
+    pub num_cpu: u64,
+    /// In MiB
+    pub memory: u64,
+    pub disk_size_gib: u64,
+}
+
+impl SizeProfile {
+    pub const ATTRIBUTES: [&'static str; 3] = ["num_cpu", "memory", "disk_size_gib"];
+
+    /// Reads a profile file, a map of profile names to their sizes
+    pub fn load(path: &Path) -> anyhow::Result<HashMap<String, SizeProfile>> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("Unable to open size profiles {path:?}: {err}"))?;
+
+        serde_yml::from_reader(file)
+            .map_err(|err| anyhow::anyhow!("Invalid size profiles {path:?}: {err}"))
+    }
+
+    pub fn attributes(&self) -> HashMap<String, ProcessableValue> {
+        HashMap::from([
+            (
+                "num_cpu".to_string(),
+                ProcessableValue::fixed(self.num_cpu.into()),
+            ),
+            (
+                "memory".to_string(),
+                ProcessableValue::fixed(self.memory.into()),
+            ),
+            (
+                "disk_size_gib".to_string(),
+                ProcessableValue::fixed(self.disk_size_gib.into()),
+            ),
+        ])
+    }
+}
+
+impl ServicePlan {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("Unable to open plan {path:?}: {err}"))?;
+
+        serde_yml::from_reader(file).map_err(|err| anyhow::anyhow!("Invalid plan {path:?}: {err}"))
+    }
+
+    /// Finds a plan referenced by name next to the given plan. The extension may be omitted.
+    pub fn referenced_path(plan_path: &Path, name: &str) -> PathBuf {
+        let path = plan_path.parent().unwrap_or(Path::new(".")).join(name);
+
+        if path.extension().is_some() {
+            return path;
+        }
+
+        ["yaml", "yml"]
+            .into_iter()
+            .map(|extension| path.with_extension(extension))
+            .find(|path| path.exists())
+            .unwrap_or(path)
+    }
+}
+
+/// Marks every object created by the plan as owned by it
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PlanOwnership {
+    #[serde(default = "PlanOwnership::default_attribute")]
+    pub attribute: String,
+    /// Defaults to the file name of the plan without its extension
+    #[serde(default)]
+    pub owner: Option<ProcessableValue>,
+}
+
+impl PlanOwnership {
+    fn default_attribute() -> String {
+        "service_plan".to_string()
+    }
+}
+
+impl Default for PlanOwnership {
+    fn default() -> Self {
+        Self {
+            attribute: Self::default_attribute(),
+            owner: None,
+        }
+    }
 }
 
 pub type ServiceVm = HashMap<String, ProcessableValue>;
@@ -14,7 +140,166 @@ pub struct Service {
     pub instances: ServiceInstances,
     #[serde(default)]
     pub firewall: ServiceFirewall,
+    /// A size profile, whose resources are set unless `vm` declares them itself
+    #[serde(default)]
+    pub size: Option<String>,
+    /// The Serveradmin state of the service's VMs
+    #[serde(default)]
+    pub state: Option<ObjectState>,
     pub vm: ServiceVm,
+    /// Further objects of the service like DNS records, database users or storage volumes
+    #[serde(default)]
+    pub resources: Vec<ServiceResource>,
+    /// Relations between the objects of the service and other objects
+    #[serde(default)]
+    pub relations: Vec<ServiceRelation>,
+}
+
+/// Sets a relation attribute of all objects of the service of one kind to the targets.
+///
+/// Values the plan does not declare anymore are removed.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ServiceRelation {
+    pub from: RelationSource,
+    pub attribute: String,
+    pub to: RelationTarget,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationSource {
+    Vms,
+    ServiceGroups,
+    Loadbalancers,
+    HealthChecks,
+    Resources,
+}
+
+impl std::fmt::Display for RelationSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelationSource::Vms => f.write_str("vms"),
+            RelationSource::ServiceGroups => f.write_str("service_groups"),
+            RelationSource::Loadbalancers => f.write_str("loadbalancers"),
+            RelationSource::HealthChecks => f.write_str("health_checks"),
+            RelationSource::Resources => f.write_str("resources"),
+        }
+    }
+}
+
+/// Objects of the plan or existing ones by hostname, and/or existing ones found by a query
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct RelationTarget {
+    #[serde(default)]
+    pub hostnames: Vec<ProcessableValue>,
+    /// Attribute values all found objects have to match
+    #[serde(default)]
+    pub query: HashMap<String, ProcessableValue>,
+}
+
+/// How objects of a kind are created. Settings left out are taken from the overridden kind.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ResourceKind {
+    /// Defaults to the name of the kind
+    #[serde(default)]
+    pub servertype: Option<String>,
+    /// The attributes taken over from the plan's target
+    #[serde(default)]
+    pub selectors: Option<Vec<SelectorAttribute>>,
+    /// Attributes set on every object of the kind, unless the plan declares them itself
+    #[serde(default)]
+    pub defaults: HashMap<String, ProcessableValue>,
+    /// The Serveradmin attributes plan settings without a fixed attribute are written to,
+    /// e.g. `algorithm: lb_algorithm` for loadbalancers
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectorAttribute {
+    Project,
+    Subproject,
+    Environment,
+    Function,
+}
+
+impl SelectorAttribute {
+    pub fn attribute(&self) -> &'static str {
+        match self {
+            SelectorAttribute::Project => "project",
+            SelectorAttribute::Subproject => "subproject",
+            SelectorAttribute::Environment => "environment",
+            SelectorAttribute::Function => "function",
+        }
+    }
+}
+
+/// An object of an arbitrary servertype, which belongs to the service
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ServiceResource {
+    /// A registered resource kind, or a servertype tagged with project, subproject and environment
+    pub kind: String,
+    pub hostname: ProcessableValue,
+    #[serde(default)]
+    pub attributes: HashMap<String, ProcessableValue>,
+    /// Relation attributes of the resource and the hostnames they point to, set once all objects exist
+    #[serde(default)]
+    pub relations: HashMap<String, Vec<ProcessableValue>>,
+}
+
+/// The Serveradmin `state` of an object
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum ObjectState {
+    Online,
+    Maintenance,
+    DeployOnline,
+    DeployOffline,
+    Retired,
+}
+
+impl ObjectState {
+    /// Whether an existing object may be moved from this state to `target`.
+    ///
+    /// Objects only leave or enter service through maintenance, retired ones are brought back the
+    /// same way.
+    pub fn can_transition(&self, target: ObjectState) -> bool {
+        use ObjectState::*;
+
+        matches!(
+            (self, target),
+            (Online, Maintenance)
+                | (Maintenance, Online | DeployOnline | DeployOffline | Retired)
+                | (DeployOnline, Online | Maintenance)
+                | (DeployOffline, Maintenance | DeployOnline)
+                | (Retired, Maintenance)
+        ) || *self == target
+    }
+}
+
+impl std::fmt::Display for ObjectState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectState::Online => f.write_str("online"),
+            ObjectState::Maintenance => f.write_str("maintenance"),
+            ObjectState::DeployOnline => f.write_str("deploy_online"),
+            ObjectState::DeployOffline => f.write_str("deploy_offline"),
+            ObjectState::Retired => f.write_str("retired"),
+        }
+    }
+}
+
+impl std::str::FromStr for ObjectState {
+    type Err = anyhow::Error;
+
+    fn from_str(state: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(state, false)
+            .map_err(|_| anyhow::anyhow!("Unknown state {state}"))
+    }
 }
 
 pub type ServiceInstances = HashMap<String, ServiceInstance>;
@@ -22,12 +307,186 @@ pub type ServiceInstances = HashMap<String, ServiceInstance>;
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ServiceInstance {
     pub replicas: u32,
-    pub project_network: ProcessableValue,
+    pub project_network: NetworkReference,
+    /// Overrides the size profile of the service
+    #[serde(default)]
+    pub size: Option<String>,
+    /// Overrides the state of the service for the VMs of this zone
+    #[serde(default)]
+    pub state: Option<ObjectState>,
+    /// Attributes overriding the service's `vm` for the VMs of this zone
+    #[serde(default)]
+    pub vm: ServiceVm,
+    /// Attributes overriding the zone's `vm` for single replicas, keyed by their index starting at 1
+    #[serde(default)]
+    pub replicas_vm: HashMap<u32, ServiceVm>,
+    /// Chooses the hypervisors of the replicas while planning
+    #[serde(default)]
+    pub placement: Option<Placement>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Placement {
+    /// Filters selecting the candidate hypervisors, in addition to `servertype: hypervisor`
+    #[serde(default)]
+    pub hypervisors: HashMap<String, ProcessableValue>,
+    /// Never put two replicas of the zone on the same hypervisor
+    #[serde(default)]
+    pub anti_affinity: bool,
+    /// A hypervisor attribute like the rack, whose values the replicas are spread across
+    #[serde(default)]
+    pub spread: Option<String>,
+    /// Hypervisors of single replicas, keyed by their index starting at 1
+    #[serde(default)]
+    pub pinned: HashMap<u32, String>,
+    /// The VM attribute, which the chosen hypervisor is written to for new VMs
+    #[serde(default = "Placement::default_attribute")]
+    pub attribute: String,
+}
+
+impl Placement {
+    fn default_attribute() -> String {
+        "hypervisor".to_string()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum NetworkType {
+    #[serde(rename = "route_network")]
+    Route,
+    #[serde(rename = "project_network")]
+    Project,
+    #[serde(rename = "public_network")]
+    Public,
+}
+
+impl NetworkType {
+    pub const ALL: [NetworkType; 3] = [
+        NetworkType::Route,
+        NetworkType::Project,
+        NetworkType::Public,
+    ];
+}
+
+impl std::fmt::Display for NetworkType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkType::Route => f.write_str("route_network"),
+            NetworkType::Project => f.write_str("project_network"),
+            NetworkType::Public => f.write_str("public_network"),
+        }
+    }
+}
+
+/// A network either given by its plain name (`"my-network"` or `!template "..."`) or explicitly
+/// by type and name (`{type: route_network, name: "my-network"}`)
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct NetworkReference {
+    #[serde(rename = "type")]
+    pub typ: Option<NetworkType>,
+    pub name: ProcessableValue,
+}
+
+impl NetworkReference {
+    pub fn render(
+        &self,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+    ) -> anyhow::Result<NetworkTarget> {
+        let serde_json::Value::String(name) = self.name.render(variables)? else {
+            return Err(anyhow::anyhow!("The network name has to be a string"));
+        };
+
+        Ok(NetworkTarget {
+            name,
+            typ: self.typ,
+        })
+    }
+}
+
+/// A rendered [`NetworkReference`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkTarget {
+    pub name: String,
+    pub typ: Option<NetworkType>,
+}
+
+impl std::fmt::Display for NetworkTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.typ {
+            Some(typ) => write!(f, "{} ({typ})", self.name),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TypedNetworkReference {
+    #[serde(rename = "type")]
+    typ: NetworkType,
+    name: ProcessableValue,
+}
+
+struct NetworkReferenceVisitor;
+
+impl<'de> Visitor<'de> for NetworkReferenceVisitor {
+    type Value = NetworkReference;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a network name or a map with type and name")
+    }
+
+    fn visit_str<E>(self, data: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.visit_string(data.to_owned())
+    }
+
+    fn visit_string<E>(self, data: String) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(NetworkReference {
+            typ: None,
+            name: ProcessableValueVisitor.visit_string(data)?,
+        })
+    }
+
+    fn visit_enum<A>(self, data: A) -> Result<Self::Value, A::Error>
+    where
+        A: EnumAccess<'de>,
+    {
+        Ok(NetworkReference {
+            typ: None,
+            name: ProcessableValueVisitor.visit_enum(data)?,
+        })
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let reference = TypedNetworkReference::deserialize(MapAccessDeserializer::new(map))?;
+
+        Ok(NetworkReference {
+            typ: Some(reference.typ),
+            name: reference.name,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for NetworkReference {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(NetworkReferenceVisitor)
+    }
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct FirewallExport {
-    pub ports: Vec<String>,
+    pub ports: Vec<PortSpec>,
     pub name: ProcessableValue,
     #[serde(default)]
     pub loadbalancer: Option<FirewallLoadbalancer>,
@@ -35,26 +494,153 @@ pub struct FirewallExport {
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ExternalFirewallRule {
-    pub ports: Vec<String>,
-    pub service: ProcessableValue,
+    pub ports: Vec<PortSpec>,
+    /// The imported service, either `service` of this plan or `other-plan#service`.
+    ///
+    /// Optional for shared service groups given as `references` together with a `client_group`
+    #[serde(default)]
+    pub service: Option<ProcessableValue>,
+    /// The project of the imported service, defaults to the current project
+    #[serde(default)]
+    pub project: Option<ProcessableValue>,
+    /// The service groups to allow traffic to, resolved from the service's exports if empty
+    #[serde(default)]
     pub references: Vec<ProcessableValue>,
+    /// The name of the client group, defaults to `{subproject}-{environment}-{service}-clients.{project}.sg`,
+    /// with the service prefixed by the project and plan it is imported from
+    #[serde(default)]
+    pub client_group: Option<ProcessableValue>,
 }
 
 #[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
 pub struct ServiceFirewall {
     #[serde(default)]
-    pub intern: Vec<String>,
+    pub intern: Vec<PortSpec>,
     #[serde(default)]
     pub export: Vec<FirewallExport>,
     #[serde(default)]
     pub import: Vec<ExternalFirewallRule>,
+    #[serde(default)]
+    pub external: Vec<ExternalPeerRule>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FirewallDirection {
+    Inbound,
+    Outbound,
+}
+
+/// Allows traffic from or to peers outside of Serveradmin's service groups
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ExternalPeerRule {
+    pub name: ProcessableValue,
+    pub direction: FirewallDirection,
+    pub ports: Vec<PortSpec>,
+    #[serde(default)]
+    pub cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub networks: Vec<NetworkReference>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct FirewallLoadbalancer {
     pub name: ProcessableValue,
-    pub public_network: ProcessableValue,
-    pub health_check: HealthCheck,
+    pub public_network: NetworkReference,
+    /// Creates one loadbalancer per zone, whose backends are only the VMs of that zone.
+    ///
+    /// The name has to contain `{zone}` then.
+    #[serde(default)]
+    pub per_zone: bool,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub health_checks: Vec<HealthCheck>,
+    #[serde(default)]
+    pub min_nodes: Option<u32>,
+    #[serde(default)]
+    pub min_nodes_action: Option<MinNodesAction>,
+    #[serde(default)]
+    pub symmetric_nat: Option<bool>,
+    #[serde(default)]
+    pub algorithm: Option<LoadbalancerAlgorithm>,
+    #[serde(default)]
+    pub persistence: Option<LoadbalancerPersistence>,
+    /// Written as `<protocol><port>[:<backend port>]` each, e.g. `tls443:80`
+    #[serde(default)]
+    pub listeners: Vec<LoadbalancerListener>,
+    /// Additional loadbalancer attributes, which have no dedicated setting
+    #[serde(default)]
+    pub extra: HashMap<String, ProcessableValue>,
+}
+
+impl FirewallLoadbalancer {
+    /// All health checks, regardless of whether they are given as `health_check` or `health_checks`
+    pub fn health_checks(&self) -> impl Iterator<Item = &HealthCheck> {
+        self.health_check.iter().chain(self.health_checks.iter())
+    }
+}
+
+/// What happens when fewer than `min_nodes` backends are healthy
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MinNodesAction {
+    #[default]
+    ForceDown,
+    ForceUp,
+    None,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadbalancerAlgorithm {
+    RoundRobin,
+    LeastConnections,
+    SourceHash,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadbalancerPersistence {
+    None,
+    SourceIp,
+    Cookie,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerProtocol {
+    Tcp,
+    Http,
+    /// Terminates TLS on the loadbalancer
+    Tls,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct LoadbalancerListener {
+    pub port: u16,
+    pub protocol: ListenerProtocol,
+    /// The port on the backends, defaults to the listener's port
+    #[serde(default)]
+    pub backend_port: Option<u16>,
+}
+
+impl std::fmt::Display for LoadbalancerListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let protocol = match self.protocol {
+            ListenerProtocol::Tcp => "tcp",
+            ListenerProtocol::Http => "http",
+            ListenerProtocol::Tls => "tls",
+        };
+
+        write!(f, "{protocol}{}", self.port)?;
+
+        if let Some(backend_port) = self.backend_port {
+            write!(f, ":{backend_port}")?;
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -64,22 +650,258 @@ pub enum HealthCheck {
     Create(HealthCheckField),
 }
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+impl HealthCheck {
+    pub fn name(&self) -> &ProcessableValue {
+        match self {
+            HealthCheck::Import { name } => name,
+            HealthCheck::Create(field) => &field.name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
 pub struct HealthCheckField {
     pub name: ProcessableValue,
     pub port: u16,
-    #[serde(rename = "type")]
-    pub typ: String,
-    #[serde(default)]
-    pub hostname: String,
-    #[serde(default)]
+    #[serde(flatten)]
+    pub check: HealthCheckType,
+    #[serde(flatten)]
+    pub timing: HealthCheckTiming,
+}
+
+impl<'de> Deserialize<'de> for HealthCheckField {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        /// The settings every type of health check has
+        #[derive(serde::Deserialize)]
+        struct Common {
+            name: ProcessableValue,
+            port: u16,
+            #[serde(flatten)]
+            timing: HealthCheckTiming,
+        }
+
+        // The common settings are split off by hand, as serde neither passes YAML tags through
+        // flattened fields nor checks the type specific fields for unknown ones then
+        let mut check = serde_yml::Mapping::deserialize(deserializer)?;
+        let mut common = serde_yml::Mapping::new();
+        for key in ["name", "port", "interval", "timeout", "rise", "fall"] {
+            if let Some(value) = check.remove(key) {
+                common.insert(key.into(), value);
+            }
+        }
+
+        let common: Common =
+            serde_yml::from_value(serde_yml::Value::Mapping(common)).map_err(D::Error::custom)?;
+        let check: HealthCheckType =
+            serde_yml::from_value(serde_yml::Value::Mapping(check)).map_err(D::Error::custom)?;
+        common.timing.validate().map_err(D::Error::custom)?;
+
+        Ok(Self {
+            name: common.name,
+            port: common.port,
+            check,
+            timing: common.timing,
+        })
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthCheckType {
+    Http(HttpHealthCheck),
+    Https(HttpHealthCheck),
+    Tcp(TcpHealthCheck),
+    Postgres(SqlHealthCheck),
+    Mysql(SqlHealthCheck),
+    Dns(DnsHealthCheck),
+}
+
+impl HealthCheckType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HealthCheckType::Http(_) => "http",
+            HealthCheckType::Https(_) => "https",
+            HealthCheckType::Tcp(_) => "tcp",
+            HealthCheckType::Postgres(_) => "postgres",
+            HealthCheckType::Mysql(_) => "mysql",
+            HealthCheckType::Dns(_) => "dns",
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpHealthCheck {
+    /// The request, e.g. `GET /health`
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub ok_codes: Vec<i32>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub drain_codes: Vec<i32>,
-    #[serde(default)]
+    /// A string the response body has to contain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect: Option<String>,
+}
+
+/// Only checks that the port accepts connections
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpHealthCheck {}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqlHealthCheck {
     pub user: String,
-    #[serde(default)]
     pub db_name: String,
-    #[serde(default)]
-    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsHealthCheck {
+    /// The name to resolve
+    pub hostname: String,
+    /// The expected answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct HealthCheckTiming {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rise: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fall: Option<u32>,
+}
+
+impl HealthCheckTiming {
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(interval), Some(timeout)) = (self.interval, self.timeout) {
+            if timeout > interval {
+                return Err(format!(
+                    "The health check timeout ({timeout}) must not exceed its interval ({interval})"
+                ));
+            }
+        }
+
+        if self.rise == Some(0) || self.fall == Some(0) {
+            return Err("The health check rise and fall counts have to be positive".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health_check(yaml: &str) -> Result<HealthCheckField, serde_yml::Error> {
+        serde_yml::from_str(yaml)
+    }
+
+    #[test]
+    fn health_check_parses_type_specific_fields() {
+        let field = health_check(
+            "name: web.hc\nport: 443\ntype: https\nquery: GET /health\nok_codes: [200]\ninterval: 10\ntimeout: 5",
+        )
+        .unwrap();
+
+        let HealthCheckType::Https(check) = field.check else {
+            panic!("expected an https check, got {:?}", field.check);
+        };
+        assert_eq!(field.port, 443);
+        assert_eq!(check.query, "GET /health");
+        assert_eq!(check.ok_codes, vec![200]);
+        assert_eq!(field.timing.interval, Some(10));
+        assert_eq!(field.timing.timeout, Some(5));
+    }
+
+    #[test]
+    fn health_check_keeps_tagged_names() {
+        let field = health_check("name: !template \"{function}.hc\"\nport: 22\ntype: tcp").unwrap();
+
+        assert!(matches!(field.check, HealthCheckType::Tcp(_)));
+        assert!(field.name.is_template);
+    }
+
+    #[test]
+    fn health_check_rejects_misspelled_fields() {
+        let err = health_check(
+            "name: db.hc\nport: 5432\ntype: postgres\nuser: check\ndb_name: app\nqeury: SELECT 1",
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("qeury"), "{err}");
+    }
+
+    #[test]
+    fn health_check_rejects_fields_of_other_types() {
+        assert!(health_check("name: web.hc\nport: 80\ntype: tcp\nquery: GET /").is_err());
+        assert!(
+            health_check("name: ns.hc\nport: 53\ntype: dns\nhostname: a.b\nok_codes: [200]")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn health_check_requires_type_specific_fields() {
+        assert!(health_check("name: db.hc\nport: 3306\ntype: mysql\nuser: check").is_err());
+        assert!(health_check("name: web.hc\nport: 80\ntype: gopher").is_err());
+    }
+
+    #[test]
+    fn health_check_validates_timing() {
+        assert!(health_check("name: a.hc\nport: 22\ntype: tcp\ninterval: 5\ntimeout: 10").is_err());
+        assert!(health_check("name: a.hc\nport: 22\ntype: tcp\nrise: 0").is_err());
+        assert!(
+            health_check("name: a.hc\nport: 22\ntype: tcp\ninterval: 5\ntimeout: 5\nfall: 3")
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn state_changes_pass_through_maintenance() {
+        use ObjectState::*;
+
+        assert!(Online.can_transition(Maintenance));
+        assert!(Maintenance.can_transition(Retired));
+        assert!(Retired.can_transition(Maintenance));
+        assert!(DeployOnline.can_transition(Online));
+        assert!(!Online.can_transition(Retired));
+        assert!(!Retired.can_transition(Online));
+        assert!(!Online.can_transition(DeployOffline));
+        assert!(!DeployOffline.can_transition(Online));
+    }
+
+    #[test]
+    fn every_state_can_stay() {
+        use clap::ValueEnum;
+
+        for state in ObjectState::value_variants() {
+            assert!(state.can_transition(*state), "{state} cannot stay");
+        }
+    }
+
+    #[test]
+    fn state_names_round_trip() {
+        use clap::ValueEnum;
+
+        for state in ObjectState::value_variants() {
+            assert_eq!(state.to_string().parse::<ObjectState>().unwrap(), *state);
+        }
+        assert!("offline".parse::<ObjectState>().is_err());
+    }
 }
@@ -0,0 +1,269 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::graph::Graph;
+use crate::port_spec::PortSpec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceGroupKind {
+    Export,
+    Import,
+    External,
+    Intern,
+}
+
+/// A service group as the processor creates it, independent of Serveradmin
+#[derive(Clone, Debug)]
+pub struct ServiceGroupSpec {
+    pub kind: ServiceGroupKind,
+    pub hostname: String,
+    /// The service, whose VMs are members of the group
+    pub function: String,
+    pub inbound: Vec<PortSpec>,
+    pub outbound: Vec<PortSpec>,
+    pub allow_from: Vec<String>,
+    pub allow_to: Vec<String>,
+    /// Peer networks, written to the attributes the service group kind maps `cidr_allow_from` and
+    /// `cidr_allow_to` to
+    pub cidr_allow_from: Vec<String>,
+    pub cidr_allow_to: Vec<String>,
+}
+
+impl ServiceGroupSpec {
+    pub fn new(kind: ServiceGroupKind, hostname: String, function: &str) -> Self {
+        Self {
+            kind,
+            hostname,
+            function: function.to_string(),
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            allow_from: Vec::new(),
+            allow_to: Vec::new(),
+            cidr_allow_from: Vec::new(),
+            cidr_allow_to: Vec::new(),
+        }
+    }
+}
+
+/// Traffic allowed from one service or peer to another
+#[derive(Clone, Debug)]
+pub struct AllowRule {
+    pub from: String,
+    pub to: String,
+    pub ports: Vec<PortSpec>,
+    /// The service groups, which allow the traffic
+    pub via: String,
+}
+
+fn intersect(outbound: &[PortSpec], inbound: &[PortSpec]) -> Vec<PortSpec> {
+    let mut ports = Vec::new();
+
+    for outbound in outbound {
+        for inbound in inbound {
+            if let Some(port) = outbound.intersection(inbound) {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+    }
+
+    ports
+}
+
+/// Computes who may talk to whom on which ports.
+///
+/// Services are represented by their function, service groups outside of the plan and external
+/// peers by their name. Traffic between two service groups is allowed on the ports, which are
+/// outbound on the source and inbound on the target.
+pub fn allow_matrix(specs: &[ServiceGroupSpec]) -> Vec<AllowRule> {
+    let by_hostname = specs
+        .iter()
+        .map(|spec| (spec.hostname.as_str(), spec))
+        .collect::<HashMap<_, _>>();
+    let mut seen = HashSet::new();
+    let mut rules = Vec::new();
+
+    let pairs = specs
+        .iter()
+        .flat_map(|source| {
+            source
+                .allow_to
+                .iter()
+                .map(move |target| (source.hostname.as_str(), target.as_str()))
+        })
+        .chain(specs.iter().flat_map(|target| {
+            target
+                .allow_from
+                .iter()
+                .map(move |source| (source.as_str(), target.hostname.as_str()))
+        }));
+
+    for (source, target) in pairs {
+        if !seen.insert((source, target)) {
+            continue;
+        }
+
+        let source_spec = by_hostname.get(source);
+        let target_spec = by_hostname.get(target);
+        let ports = match (source_spec, target_spec) {
+            (Some(source), Some(target)) => intersect(&source.outbound, &target.inbound),
+            (Some(source), None) => source.outbound.clone(),
+            (None, Some(target)) => target.inbound.clone(),
+            (None, None) => Vec::new(),
+        };
+
+        if ports.is_empty() {
+            continue;
+        }
+
+        rules.push(AllowRule {
+            from: source_spec
+                .map(|spec| spec.function.clone())
+                .unwrap_or(source.to_string()),
+            to: target_spec
+                .map(|spec| spec.function.clone())
+                .unwrap_or(target.to_string()),
+            ports,
+            via: if source == target {
+                source.to_string()
+            } else {
+                format!("{source} -> {target}")
+            },
+        });
+    }
+
+    for spec in specs {
+        for cidr in &spec.cidr_allow_to {
+            rules.push(AllowRule {
+                from: spec.function.clone(),
+                to: cidr.clone(),
+                ports: spec.outbound.clone(),
+                via: spec.hostname.clone(),
+            });
+        }
+
+        for cidr in &spec.cidr_allow_from {
+            rules.push(AllowRule {
+                from: cidr.clone(),
+                to: spec.function.clone(),
+                ports: spec.inbound.clone(),
+                via: spec.hostname.clone(),
+            });
+        }
+    }
+
+    rules
+}
+
+pub fn allow_matrix_graph(specs: &[ServiceGroupSpec], rules: &[AllowRule]) -> Graph {
+    let services = specs
+        .iter()
+        .map(|spec| spec.function.as_str())
+        .collect::<HashSet<_>>();
+    let mut graph = Graph::default();
+
+    for rule in rules {
+        for node in [&rule.from, &rule.to] {
+            match services.contains(node.as_str()) {
+                true => graph.add_node(node, "service"),
+                false => graph.add_node(node, "peer"),
+            }
+        }
+
+        graph.add_edge(
+            &rule.from,
+            &rule.to,
+            rule.ports
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::port_spec::ports;
+
+    fn spec(kind: ServiceGroupKind, hostname: &str, function: &str) -> ServiceGroupSpec {
+        ServiceGroupSpec::new(kind, hostname.to_string(), function)
+    }
+
+    #[test]
+    fn allow_matrix_intersects_outbound_and_inbound_ports() {
+        let mut export = spec(ServiceGroupKind::Export, "db.sg", "db");
+        export.inbound = ports(&["tcp5432", "tcp9100"]);
+        let mut import = spec(ServiceGroupKind::Import, "web-db-clients.sg", "web");
+        import.outbound = ports(&["tcp5000-6000"]);
+        import.allow_to = vec!["db.sg".to_string()];
+
+        let rules = allow_matrix(&[export, import]);
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].from, "web");
+        assert_eq!(rules[0].to, "db");
+        assert_eq!(rules[0].ports, ports(&["tcp5432"]));
+        assert_eq!(rules[0].via, "web-db-clients.sg -> db.sg");
+    }
+
+    #[test]
+    fn allow_matrix_skips_pairs_without_common_ports() {
+        let mut export = spec(ServiceGroupKind::Export, "db.sg", "db");
+        export.inbound = ports(&["tcp5432"]);
+        let mut import = spec(ServiceGroupKind::Import, "web-db-clients.sg", "web");
+        import.outbound = ports(&["udp5432"]);
+        import.allow_to = vec!["db.sg".to_string()];
+
+        assert!(allow_matrix(&[export, import]).is_empty());
+    }
+
+    #[test]
+    fn allow_matrix_lists_a_pair_once() {
+        let mut export = spec(ServiceGroupKind::Export, "db.sg", "db");
+        export.inbound = ports(&["tcp5432"]);
+        export.allow_from = vec!["web-db-clients.sg".to_string()];
+        let mut import = spec(ServiceGroupKind::Import, "web-db-clients.sg", "web");
+        import.outbound = ports(&["tcp5432"]);
+        import.allow_to = vec!["db.sg".to_string()];
+
+        assert_eq!(allow_matrix(&[export, import]).len(), 1);
+    }
+
+    #[test]
+    fn allow_matrix_takes_the_ports_of_groups_outside_the_plan() {
+        let mut import = spec(ServiceGroupKind::Import, "web-dns-clients.sg", "web");
+        import.outbound = ports(&["both53"]);
+        import.allow_to = vec!["dns.infra.sg".to_string()];
+
+        let rules = allow_matrix(&[import]);
+
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].to, "dns.infra.sg");
+        assert_eq!(rules[0].ports, ports(&["both53"]));
+    }
+
+    #[test]
+    fn allow_matrix_adds_external_peers() {
+        let mut external = spec(ServiceGroupKind::External, "web-external.sg", "web");
+        external.inbound = ports(&["tcp443"]);
+        external.outbound = ports(&["tcp25"]);
+        external.cidr_allow_from = vec!["203.0.113.0/24".to_string()];
+        external.cidr_allow_to = vec!["198.51.100.0/24".to_string()];
+
+        let rules = allow_matrix(&[external]);
+        let rule = |from: &str, to: &str| {
+            rules
+                .iter()
+                .find(|rule| rule.from == from && rule.to == to)
+                .map(|rule| rule.ports.clone())
+        };
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rule("203.0.113.0/24", "web"), Some(ports(&["tcp443"])));
+        assert_eq!(rule("web", "198.51.100.0/24"), Some(ports(&["tcp25"])));
+    }
+}
@@ -0,0 +1,109 @@
+/// A directed graph, which can be rendered for documentation and reviews
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct GraphNode {
+    pub id: String,
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    pub label: String,
+}
+
+impl Graph {
+    pub fn add_node(&mut self, id: impl ToString, kind: &str) {
+        let id = id.to_string();
+
+        if !self.nodes.iter().any(|node| node.id == id) {
+            self.nodes.push(GraphNode {
+                id,
+                kind: kind.to_string(),
+            });
+        }
+    }
+
+    pub fn add_edge(&mut self, from: impl ToString, to: impl ToString, label: impl ToString) {
+        self.edges.push(GraphEdge {
+            from: from.to_string(),
+            to: to.to_string(),
+            label: label.to_string(),
+        });
+    }
+
+    pub fn to_dot(&self) -> String {
+        let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut dot = String::from("digraph service_plan {\n    rankdir=LR;\n");
+
+        for node in &self.nodes {
+            let shape = match node.kind.as_str() {
+                "service" => "box",
+                "zone" => "folder",
+                "service_group" => "hexagon",
+                "loadbalancer" => "invtrapezium",
+                "health_check" => "note",
+                "peer" => "diamond",
+                _ => "ellipse",
+            };
+
+            dot.push_str(&format!("    \"{}\" [shape={shape}];\n", escape(&node.id)));
+        }
+
+        for edge in &self.edges {
+            dot.push_str(&format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                escape(&edge.from),
+                escape(&edge.to),
+                escape(&edge.label)
+            ));
+        }
+
+        dot.push('}');
+
+        dot
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let escape = |value: &str| value.replace('"', "#quot;");
+        let node_id = |id: &str| {
+            self.nodes
+                .iter()
+                .position(|node| node.id == id)
+                .map(|index| format!("n{index}"))
+                .unwrap_or_default()
+        };
+        let mut mermaid = String::from("flowchart LR\n");
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            mermaid.push_str(&format!("    n{index}[\"{}\"]\n", escape(&node.id)));
+        }
+
+        for edge in &self.edges {
+            if edge.label.is_empty() {
+                mermaid.push_str(&format!(
+                    "    {} --> {}\n",
+                    node_id(&edge.from),
+                    node_id(&edge.to)
+                ));
+
+                continue;
+            }
+
+            mermaid.push_str(&format!(
+                "    {} -- \"{}\" --> {}\n",
+                node_id(&edge.from),
+                escape(&edge.label),
+                node_id(&edge.to)
+            ));
+        }
+
+        mermaid
+    }
+}
@@ -0,0 +1,203 @@
+use std::process::Stdio;
+
+use adminapi::new_object::NewObject;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::PlanHooks;
+
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    PreApply,
+    PostApply,
+    PostObjectCreate,
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HookEvent::PreApply => f.write_str("pre_apply"),
+            HookEvent::PostApply => f.write_str("post_apply"),
+            HookEvent::PostObjectCreate => f.write_str("post_object_create"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookAction {
+    Create,
+    Update,
+}
+
+/// An object affected by the apply, as handed to the hooks
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct HookObject {
+    pub hostname: String,
+    pub servertype: String,
+    pub action: HookAction,
+}
+
+impl HookObject {
+    pub fn describe(object: &NewObject) -> Self {
+        Self {
+            hostname: object
+                .get("hostname")
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            servertype: object
+                .get("servertype")
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            action: if object.is_new() {
+                HookAction::Create
+            } else {
+                HookAction::Update
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct HookPayload<'a> {
+    event: HookEvent,
+    project: &'a str,
+    subproject: &'a str,
+    environment: &'a str,
+    objects: &'a [HookObject],
+}
+
+/// Runs the hooks of a plan with `sh -c` for the plan's target
+pub struct HookRunner {
+    hooks: PlanHooks,
+    project: String,
+    subproject: String,
+    environment: String,
+}
+
+impl HookRunner {
+    pub fn new(hooks: PlanHooks, project: String, subproject: String, environment: String) -> Self {
+        Self {
+            hooks,
+            project,
+            subproject,
+            environment,
+        }
+    }
+
+    /// Runs the pre-apply hooks until the first one fails
+    pub async fn pre_apply(&self, objects: &[HookObject]) -> anyhow::Result<()> {
+        for command in &self.hooks.pre_apply {
+            self.run(command, HookEvent::PreApply, objects).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the post-apply hooks, failures are only reported
+    pub async fn post_apply(&self, objects: &[HookObject]) {
+        for command in &self.hooks.post_apply {
+            self.run_reported(command, HookEvent::PostApply, objects)
+                .await;
+        }
+    }
+
+    /// Runs the post-object-create hooks for every created object, failures are only reported
+    pub async fn post_object_create(&self, objects: &[HookObject]) {
+        for object in objects
+            .iter()
+            .filter(|object| object.action == HookAction::Create)
+        {
+            for command in &self.hooks.post_object_create {
+                self.run_reported(
+                    command,
+                    HookEvent::PostObjectCreate,
+                    std::slice::from_ref(object),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn run_reported(&self, command: &str, event: HookEvent, objects: &[HookObject]) {
+        if let Err(err) = self.run(command, event, objects).await {
+            let style = console::Style::new().yellow();
+            println!("{}", style.apply_to(format!("Warning: {err}")));
+        }
+    }
+
+    async fn run(
+        &self,
+        command: &str,
+        event: HookEvent,
+        objects: &[HookObject],
+    ) -> anyhow::Result<()> {
+        let payload = serde_json::to_string(&HookPayload {
+            event,
+            project: &self.project,
+            subproject: &self.subproject,
+            environment: &self.environment,
+            objects,
+        })?;
+
+        // The objects only go to stdin, as a single variable is too small for large plans
+        let mut process = tokio::process::Command::new("sh");
+        process
+            .arg("-c")
+            .arg(command)
+            .env("SERVICE_PLAN_EVENT", event.to_string())
+            .env("SERVICE_PLAN_PROJECT", &self.project)
+            .env("SERVICE_PLAN_SUBPROJECT", &self.subproject)
+            .env("SERVICE_PLAN_ENVIRONMENT", &self.environment)
+            .stdin(Stdio::piped());
+        if let (HookEvent::PostObjectCreate, [object]) = (event, objects) {
+            process.env("SERVICE_PLAN_HOSTNAME", &object.hostname);
+        }
+
+        let mut child = process
+            .spawn()
+            .map_err(|err| anyhow::anyhow!("Unable to run the {event} hook {command:?}: {err}"))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // Hooks, which do not read their input, close stdin early
+            let _ = stdin.write_all(payload.as_bytes()).await;
+        }
+
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "The {event} hook {command:?} failed with {status}"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_names_event_and_actions() {
+        let objects = [HookObject {
+            hostname: "web1".to_string(),
+            servertype: "vm".to_string(),
+            action: HookAction::Create,
+        }];
+        let payload = serde_json::to_value(HookPayload {
+            event: HookEvent::PostObjectCreate,
+            project: "shop",
+            subproject: "web",
+            environment: "production",
+            objects: &objects,
+        })
+        .unwrap();
+
+        assert_eq!(payload["event"], "post_object_create");
+        assert_eq!(payload["objects"][0]["action"], "create");
+        assert_eq!(payload["objects"][0]["hostname"], "web1");
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config::{ResourceKind, SelectorAttribute};
+use crate::processable_value::ProcessableValue;
+
+/// A resource kind with all settings resolved
+#[derive(Clone, Debug)]
+pub struct Kind {
+    pub servertype: String,
+    pub selectors: Vec<SelectorAttribute>,
+    pub defaults: HashMap<String, ProcessableValue>,
+    pub attributes: HashMap<String, String>,
+}
+
+impl Kind {
+    /// The Serveradmin attribute the given plan setting is written to
+    pub fn attribute(&self, setting: &str) -> anyhow::Result<&str> {
+        self.attributes
+            .get(setting)
+            .map(String::as_str)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No attribute is configured for {setting} of {}, add it to the attributes of its kind",
+                    self.servertype
+                )
+            })
+    }
+}
+
+/// The kinds of objects the processor creates, extensible by kinds files and plans
+#[derive(Clone, Debug)]
+pub struct ResourceKinds {
+    kinds: HashMap<String, ResourceKind>,
+}
+
+impl ResourceKinds {
+    pub fn builtin() -> Self {
+        use SelectorAttribute::*;
+
+        let kind = |servertype: &str, selectors: &[SelectorAttribute]| ResourceKind {
+            servertype: Some(servertype.to_string()),
+            selectors: Some(selectors.to_vec()),
+            defaults: HashMap::new(),
+            attributes: HashMap::new(),
+        };
+
+        Self {
+            kinds: HashMap::from([
+                (
+                    "vm".to_string(),
+                    kind("vm", &[Project, Subproject, Environment, Function]),
+                ),
+                (
+                    "service_group".to_string(),
+                    kind(
+                        "service_group",
+                        &[Project, Subproject, Environment, Function],
+                    ),
+                ),
+                (
+                    "loadbalancer".to_string(),
+                    kind(
+                        "loadbalancer",
+                        &[Project, Subproject, Environment, Function],
+                    ),
+                ),
+                (
+                    "health_check".to_string(),
+                    kind("health_check", &[Project, Subproject]),
+                ),
+            ]),
+        }
+    }
+
+    /// Reads a kinds file, a map of kind names to their settings
+    pub fn load(path: &Path) -> anyhow::Result<HashMap<String, ResourceKind>> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| anyhow::anyhow!("Unable to open kinds file {path:?}: {err}"))?;
+
+        serde_yml::from_reader(file)
+            .map_err(|err| anyhow::anyhow!("Invalid kinds file {path:?}: {err}"))
+    }
+
+    /// Adds new kinds and overrides the given settings of existing ones
+    pub fn extend(&mut self, kinds: &HashMap<String, ResourceKind>) -> &mut Self {
+        for (name, kind) in kinds {
+            let existing = self.kinds.entry(name.clone()).or_default();
+
+            if let Some(servertype) = &kind.servertype {
+                existing.servertype = Some(servertype.clone());
+            }
+            if let Some(selectors) = &kind.selectors {
+                existing.selectors = Some(selectors.clone());
+            }
+            existing
+                .defaults
+                .extend(kind.defaults.iter().map(|(k, v)| (k.clone(), v.clone())));
+            existing
+                .attributes
+                .extend(kind.attributes.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+
+        self
+    }
+
+    /// Unknown kinds are taken as servertype tagged with project, subproject and environment
+    pub fn get(&self, name: &str) -> Kind {
+        let kind = self.kinds.get(name).cloned().unwrap_or_default();
+
+        Kind {
+            servertype: kind.servertype.unwrap_or(name.to_string()),
+            selectors: kind.selectors.unwrap_or(vec![
+                SelectorAttribute::Project,
+                SelectorAttribute::Subproject,
+                SelectorAttribute::Environment,
+            ]),
+            defaults: kind.defaults,
+            attributes: kind.attributes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(yaml: &str) -> HashMap<String, ResourceKind> {
+        serde_yml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn get_falls_back_to_the_name_as_servertype() {
+        let kind = ResourceKinds::builtin().get("dns_record");
+
+        assert_eq!(kind.servertype, "dns_record");
+        assert_eq!(
+            kind.selectors,
+            vec![
+                SelectorAttribute::Project,
+                SelectorAttribute::Subproject,
+                SelectorAttribute::Environment,
+            ]
+        );
+        assert!(kind.defaults.is_empty());
+    }
+
+    #[test]
+    fn extend_overrides_only_the_given_settings() {
+        let mut resource_kinds = ResourceKinds::builtin();
+        resource_kinds.extend(&kinds(
+            "vm:\n  defaults:\n    os: bookworm\n  attributes:\n    hypervisor_group: hv_group",
+        ));
+        let vm = resource_kinds.get("vm");
+
+        assert_eq!(vm.servertype, "vm");
+        assert_eq!(vm.selectors.len(), 4);
+        assert!(vm.defaults.contains_key("os"));
+        assert_eq!(vm.attribute("hypervisor_group").unwrap(), "hv_group");
+    }
+
+    #[test]
+    fn later_extensions_win() {
+        let mut resource_kinds = ResourceKinds::builtin();
+        resource_kinds
+            .extend(&kinds(
+                "dns_record:\n  servertype: dns\n  selectors: [project]\n  defaults:\n    ttl: 300",
+            ))
+            .extend(&kinds(
+                "dns_record:\n  defaults:\n    ttl: 60\n    class: IN",
+            ));
+        let kind = resource_kinds.get("dns_record");
+
+        assert_eq!(kind.servertype, "dns");
+        assert_eq!(kind.selectors, vec![SelectorAttribute::Project]);
+        assert_eq!(kind.defaults.len(), 2);
+        assert_eq!(
+            kind.defaults["ttl"].content,
+            Some(serde_json::Value::from(60))
+        );
+    }
+
+    #[test]
+    fn attribute_requires_a_mapping() {
+        let kind = ResourceKinds::builtin().get("loadbalancer");
+
+        assert!(kind.attribute("algorithm").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::port_spec::PortSpec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintSeverity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for LintSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LintSeverity::Error => f.write_str("error"),
+            LintSeverity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// A problem in the firewall definitions of a service
+#[derive(Clone, Debug)]
+pub struct LintFinding {
+    pub severity: LintSeverity,
+    pub service: String,
+    pub message: String,
+}
+
+impl LintFinding {
+    pub fn error(service: &str, message: String) -> Self {
+        Self {
+            severity: LintSeverity::Error,
+            service: service.to_string(),
+            message,
+        }
+    }
+
+    pub fn warning(service: &str, message: String) -> Self {
+        Self {
+            severity: LintSeverity::Warning,
+            service: service.to_string(),
+            message,
+        }
+    }
+}
+
+/// Flags ports listed more than once or overlapping each other in the same rule
+pub fn duplicate_ports(service: &str, location: &str, ports: &[PortSpec]) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+
+    for (index, port) in ports.iter().enumerate() {
+        for other in &ports[index + 1..] {
+            if port == other {
+                findings.push(LintFinding::warning(
+                    service,
+                    format!("{location} lists {port} more than once"),
+                ));
+            } else if port.overlaps(other) {
+                findings.push(LintFinding::warning(
+                    service,
+                    format!("{location} lists the overlapping ports {port} and {other}"),
+                ));
+            }
+        }
+    }
+
+    findings
+}
+
+/// Finds the ports of `imported`, which are left after taking away all `exported` ports
+pub fn uncovered_ports(imported: &[PortSpec], exported: &[PortSpec]) -> Vec<PortSpec> {
+    let mut uncovered = imported.to_vec();
+    for export in exported {
+        uncovered = uncovered
+            .iter()
+            .flat_map(|port| port.subtract(export))
+            .collect();
+    }
+
+    uncovered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::port_spec::ports;
+
+    #[test]
+    fn uncovered_ports_combines_exports() {
+        assert!(uncovered_ports(
+            &ports(&["tcp8000-8100"]),
+            &ports(&["tcp8000-8050", "tcp8051-8100"])
+        )
+        .is_empty());
+        assert!(uncovered_ports(&ports(&["both53"]), &ports(&["udp53", "tcp53"])).is_empty());
+    }
+
+    #[test]
+    fn uncovered_ports_reports_the_gaps() {
+        assert_eq!(
+            uncovered_ports(
+                &ports(&["tcp8000-8100", "tcp443"]),
+                &ports(&["tcp8000-8040", "tcp8061-8100"])
+            ),
+            ports(&["tcp8041-8060", "tcp443"])
+        );
+        assert_eq!(uncovered_ports(&ports(&["tcp80"]), &[]), ports(&["tcp80"]));
+    }
+
+    #[test]
+    fn duplicate_ports_warns_about_repeats_and_overlaps() {
+        let findings = duplicate_ports("web", "intern", &ports(&["tcp80", "tcp80", "tcp79-81"]));
+
+        assert_eq!(findings.len(), 3);
+        assert!(findings
+            .iter()
+            .all(|finding| finding.severity == LintSeverity::Warning));
+        assert!(findings[0].message.contains("more than once"));
+        assert!(duplicate_ports("web", "intern", &ports(&["tcp80", "udp80"])).is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A lock file, which prevents two applies from handing out the same IPs at the same time.
+///
+/// The file is created exclusively and removed again once the lock is dropped.
+pub struct PlanLock {
+    path: PathBuf,
+}
+
+impl PlanLock {
+    pub fn acquire(path: &Path) -> anyhow::Result<Self> {
+        let mut file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                let holder = std::fs::read_to_string(path).unwrap_or_default();
+
+                return Err(anyhow::anyhow!(
+                    "The lock {path:?} is held by {:?}. Remove it if that apply is no longer running",
+                    holder.trim()
+                ));
+            }
+            Err(err) => return Err(anyhow::anyhow!("Unable to create lock {path:?}: {err}")),
+        };
+
+        writeln!(
+            file,
+            "{}@{} (pid {})",
+            std::env::var("USER").unwrap_or_default(),
+            std::env::var("HOSTNAME").unwrap_or_default(),
+            std::process::id()
+        )?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PlanLock {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
@@ -1,3 +1,7 @@
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use adminapi::new_object::NewObject;
 use anyhow::Context;
 use clap::Parser;
 use indicatif::{ProgressBar, ProgressStyle};
@@ -5,12 +9,26 @@ use term_table::row::Row;
 use term_table::table_cell::TableCell;
 
 use crate::cli::show_spinner;
-use crate::config::ServicePlan;
-use crate::plan_processor::ServicePlanProcessor;
+use crate::config::{ObjectState, ServicePlan, SizeProfile};
+use crate::hooks::{HookObject, HookRunner};
+use crate::kinds::ResourceKinds;
+use crate::lint::{LintFinding, LintSeverity};
+use crate::lock::PlanLock;
+use crate::plan_processor::{
+    HypervisorCapacity, IpReassignment, NetworkUsage, PlannedChange, ServicePlanProcessor,
+};
 
 mod cli;
 mod config;
+mod firewall;
+mod graph;
+mod hooks;
+mod kinds;
+mod lint;
+mod lock;
+mod placement;
 mod plan_processor;
+mod port_spec;
 mod processable_value;
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -19,129 +37,610 @@ struct BriefServerObject {
     servertype: String,
     #[serde(default)]
     state: String,
+    #[serde(flatten)]
+    other: std::collections::HashMap<String, serde_json::Value>,
 }
 
 async fn show_unmanaged_objects(
     managed_objects: Vec<String>,
+    (attribute, owner): (String, String),
     project: String,
     subproject: String,
     environment: String,
 ) -> anyhow::Result<()> {
-    let all_selected_objects = adminapi::query::Query::builder()
+    let restrict = ["hostname", "servertype", "state", attribute.as_str()];
+
+    let undeclared_objects = adminapi::query::Query::builder()
+        .filter(attribute.as_str(), owner.clone())
+        .filter("project", project.clone())
+        .filter("subproject", subproject.clone())
+        .filter("environment", environment.clone())
+        .restrict(restrict)
+        .build()
+        .request_typed::<BriefServerObject>()
+        .await?
+        .all()
+        .into_iter()
+        .filter(|obj| !managed_objects.contains(&obj.attributes.hostname))
+        .collect::<Vec<_>>();
+    let unowned_objects = adminapi::query::Query::builder()
         .filter("project", project.clone())
         .filter("subproject", subproject.clone())
         .filter("environment", environment.clone())
-        .restrict(["hostname", "servertype", "state"])
+        .restrict(restrict)
         .build()
         .request_typed::<BriefServerObject>()
         .await?
         .all()
         .into_iter()
         .filter(|obj| !managed_objects.contains(&obj.attributes.hostname))
+        .filter(|obj| {
+            !matches!(
+                obj.attributes.other.get(&attribute),
+                Some(serde_json::Value::String(current)) if *current == owner
+            )
+        })
         .collect::<Vec<_>>();
 
-    if all_selected_objects.is_empty() {
+    let header_style = console::Style::new().bold();
+
+    if !undeclared_objects.is_empty() {
+        let mut table = term_table::Table::new();
+        table.add_row(Row::new(vec![
+            TableCell::new(header_style.apply_to("hostname")),
+            TableCell::new(header_style.apply_to("servertype")),
+            TableCell::new(header_style.apply_to("state")),
+        ]));
+        for obj in undeclared_objects {
+            table.add_row(Row::new(vec![
+                TableCell::new(obj.attributes.hostname),
+                TableCell::new(obj.attributes.servertype),
+                TableCell::new(obj.attributes.state),
+            ]));
+        }
+
+        println!("\nObjects owned by this plan ({attribute}={owner}), which it does not declare anymore:");
+        println!("{}", table.render());
+    }
+
+    if !unowned_objects.is_empty() {
+        let mut table = term_table::Table::new();
+        table.add_row(Row::new(vec![
+            TableCell::new(header_style.apply_to("hostname")),
+            TableCell::new(header_style.apply_to("servertype")),
+            TableCell::new(header_style.apply_to("state")),
+            TableCell::new(header_style.apply_to("owner")),
+        ]));
+        for obj in unowned_objects {
+            let owner = match obj.attributes.other.get(&attribute) {
+                Some(serde_json::Value::String(current)) if !current.is_empty() => current.clone(),
+                _ => "-".to_string(),
+            };
+
+            table.add_row(Row::new(vec![
+                TableCell::new(obj.attributes.hostname),
+                TableCell::new(obj.attributes.servertype),
+                TableCell::new(obj.attributes.state),
+                TableCell::new(owner),
+            ]));
+        }
+
+        println!("\nOther objects with the given selector (project={project} subproject={subproject} environment={environment}), which are not owned by this plan:");
+        println!("{}", table.render());
+    }
+
+    println!();
+
+    Ok(())
+}
+
+fn show_network_usage(network_usage: &[NetworkUsage], warn_utilization: u8) -> anyhow::Result<()> {
+    if network_usage.is_empty() {
+        return Ok(());
+    }
+
+    let header_style = console::Style::new().bold();
+    let warning_style = console::Style::new().yellow().bold();
+    let error_style = console::Style::new().red().bold();
+    let mut table = term_table::Table::new();
+    table.add_row(Row::new(vec![
+        TableCell::new(header_style.apply_to("network")),
+        TableCell::new(header_style.apply_to("size")),
+        TableCell::new(header_style.apply_to("taken")),
+        TableCell::new(header_style.apply_to("allocated")),
+        TableCell::new(header_style.apply_to("remaining")),
+        TableCell::new(header_style.apply_to("utilization")),
+    ]));
+
+    let mut warnings = Vec::new();
+    let mut exhausted = Vec::new();
+    for usage in network_usage {
+        let utilization = format!("{:.1}%", usage.utilization());
+        let utilization = if usage.missing > 0 {
+            exhausted.push(format!(
+                "Network {} is missing {} address(es) for this plan",
+                usage.network, usage.missing
+            ));
+
+            error_style.apply_to(utilization)
+        } else if usage.utilization() > warn_utilization as f64 {
+            warnings.push(format!(
+                "Network {} will be above {warn_utilization}% utilization",
+                usage.network
+            ));
+
+            warning_style.apply_to(utilization)
+        } else {
+            console::Style::new().apply_to(utilization)
+        };
+
+        table.add_row(Row::new(vec![
+            TableCell::new(&usage.network),
+            TableCell::new(usage.size),
+            TableCell::new(usage.taken),
+            TableCell::new(usage.allocated),
+            TableCell::new(usage.remaining()),
+            TableCell::new(utilization),
+        ]));
+    }
+
+    println!("\nIP allocation of the touched networks:\n");
+    println!("{}", table.render());
+
+    for warning in warnings {
+        println!("{} {warning}", warning_style.apply_to("Warning:"));
+    }
+
+    if !exhausted.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The plan cannot be satisfied:\n{}",
+            exhausted.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+fn show_hypervisor_capacity(
+    capacities: &[HypervisorCapacity],
+    unchecked_vms: &[String],
+) -> anyhow::Result<()> {
+    let warning_style = console::Style::new().yellow().bold();
+    if !unchecked_vms.is_empty() {
+        println!(
+            "{} The capacity for {} is not checked, give them a placement or map hypervisor_group in the vm kind",
+            warning_style.apply_to("Warning:"),
+            unchecked_vms.join(", ")
+        );
+    }
+
+    if capacities.is_empty() {
         return Ok(());
     }
 
     let header_style = console::Style::new().bold();
-    let mut unmanaged_object_table = term_table::Table::new();
-    unmanaged_object_table.add_row(Row::new(vec![
+    let error_style = console::Style::new().red().bold();
+    let mut table = term_table::Table::new();
+    let mut header = vec![TableCell::new(header_style.apply_to("capacity"))];
+    header.extend(SizeProfile::ATTRIBUTES.iter().map(|attribute| {
+        TableCell::new(header_style.apply_to(format!("{attribute} (used+planned/total)")))
+    }));
+    table.add_row(Row::new(header));
+
+    let mut exceeded = Vec::new();
+    for capacity in capacities {
+        let over = capacity.exceeded();
+        let mut row = vec![TableCell::new(format!(
+            "{} {}",
+            capacity.scope, capacity.name
+        ))];
+        let amounts = capacity
+            .used
+            .values()
+            .into_iter()
+            .zip(capacity.planned.values())
+            .zip(capacity.total.values());
+        for (((attribute, used), (_, planned)), (_, total)) in amounts {
+            let usage = format!("{used}+{planned}/{total}");
+
+            row.push(if over.contains(&attribute) {
+                TableCell::new(error_style.apply_to(usage))
+            } else {
+                TableCell::new(usage)
+            });
+        }
+        table.add_row(Row::new(row));
+
+        if !over.is_empty() {
+            exceeded.push(format!(
+                "The {} {} has not enough {}",
+                capacity.scope,
+                capacity.name,
+                over.join(", ")
+            ));
+        }
+    }
+
+    println!("\nCapacity of the hypervisors and hypervisor groups of the VMs:\n");
+    println!("{}", table.render());
+
+    if !exceeded.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The plan cannot be satisfied:\n{}",
+            exceeded.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+fn show_planned_changes(changes: &[PlannedChange]) {
+    if changes.is_empty() {
+        return;
+    }
+
+    let header_style = console::Style::new().bold();
+    let mut table = term_table::Table::new();
+    table.add_row(Row::new(vec![
         TableCell::new(header_style.apply_to("hostname")),
-        TableCell::new(header_style.apply_to("servertype")),
-        TableCell::new(header_style.apply_to("state")),
+        TableCell::new(header_style.apply_to("attribute")),
+        TableCell::new(header_style.apply_to("change")),
     ]));
-    for obj in all_selected_objects {
-        unmanaged_object_table.add_row(Row::new(vec![
-            TableCell::new(obj.attributes.hostname),
-            TableCell::new(obj.attributes.servertype),
-            TableCell::new(obj.attributes.state),
+
+    for change in changes {
+        table.add_row(Row::new(vec![
+            TableCell::new(&change.hostname),
+            TableCell::new(&change.attribute),
+            TableCell::new(&change.change),
         ]));
     }
 
-    println!("\nOther (unmanaged) objects with the given selector (project={project} subproject={subproject} environment={environment}):");
+    println!("\nThe following attribute changes are included:\n");
+    println!("{}", table.render());
+}
 
-    println!("{}", unmanaged_object_table.render());
+fn show_ip_reassignments(reassignments: &[IpReassignment]) {
+    if reassignments.is_empty() {
+        return;
+    }
 
-    println!();
+    let header_style = console::Style::new().bold();
+    let mut table = term_table::Table::new();
+    table.add_row(Row::new(vec![
+        TableCell::new(header_style.apply_to("hostname")),
+        TableCell::new(header_style.apply_to("network")),
+        TableCell::new(header_style.apply_to("planned IP")),
+        TableCell::new(header_style.apply_to("taken by")),
+        TableCell::new(header_style.apply_to("new IP")),
+    ]));
+
+    for reassignment in reassignments {
+        table.add_row(Row::new(vec![
+            TableCell::new(&reassignment.hostname),
+            TableCell::new(&reassignment.network),
+            TableCell::new(reassignment.old_ip),
+            TableCell::new(reassignment.holders.join(", ")),
+            TableCell::new(reassignment.new_ip),
+        ]));
+    }
+
+    println!("\nThe following IPs have been taken since planning and were reallocated:\n");
+    println!("{}", table.render());
+}
+
+/// Prints the findings and fails if any of them is an error
+fn show_lint_findings(findings: &[LintFinding]) -> anyhow::Result<()> {
+    if findings.is_empty() {
+        return Ok(());
+    }
+
+    let header_style = console::Style::new().bold();
+    let warning_style = console::Style::new().yellow().bold();
+    let error_style = console::Style::new().red().bold();
+    let mut table = term_table::Table::new();
+    table.add_row(Row::new(vec![
+        TableCell::new(header_style.apply_to("severity")),
+        TableCell::new(header_style.apply_to("service")),
+        TableCell::new(header_style.apply_to("finding")),
+    ]));
+
+    for finding in findings {
+        let style = match finding.severity {
+            LintSeverity::Error => &error_style,
+            LintSeverity::Warning => &warning_style,
+        };
+
+        table.add_row(Row::new(vec![
+            TableCell::new(style.apply_to(finding.severity)),
+            TableCell::new(&finding.service),
+            TableCell::new(&finding.message),
+        ]));
+    }
+
+    println!("\nFirewall lint:\n");
+    println!("{}", table.render());
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == LintSeverity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow::anyhow!(
+            "The firewall lint reported {errors} error(s)"
+        ));
+    }
 
     Ok(())
 }
 
-async fn apply(args: crate::cli::Apply) -> anyhow::Result<()> {
+fn load_processor(target: &crate::cli::PlanTarget) -> anyhow::Result<ServicePlanProcessor> {
     let stop = show_spinner("Reading service plan")?;
-    let crate::cli::Apply {
+    let plan = ServicePlan::load(&target.plan)?;
+    let mut processor = ServicePlanProcessor::new(plan);
+    processor
+        .plan_path(target.plan.clone())
+        .project(target.project.clone())
+        .subproject(target.subproject.clone())
+        .environment(target.environment.clone());
+    if let Some(kinds) = &target.kinds {
+        processor.kinds(&ResourceKinds::load(kinds)?);
+    }
+    if let Some(sizes) = &target.sizes {
+        processor.sizes(SizeProfile::load(sizes)?);
+    }
+    stop();
+
+    Ok(processor)
+}
+
+async fn networks(args: crate::cli::Networks) -> anyhow::Result<()> {
+    let processor = load_processor(&args.target)?;
+
+    let stop = show_spinner("Resolving networks")?;
+    let references = processor.network_references()?;
+    let resolved = futures::future::join_all(
+        references
+            .iter()
+            .map(|(_, network)| processor.resolve_network(network)),
+    )
+    .await;
+    stop();
+
+    let header_style = console::Style::new().bold();
+    let error_style = console::Style::new().red();
+    let mut table = term_table::Table::new();
+    table.add_row(Row::new(vec![
+        TableCell::new(header_style.apply_to("reference")),
+        TableCell::new(header_style.apply_to("name")),
+        TableCell::new(header_style.apply_to("type")),
+        TableCell::new(header_style.apply_to("resolved type")),
+        TableCell::new(header_style.apply_to("network")),
+    ]));
+
+    let mut failed = false;
+    for ((location, network), resolved) in references.iter().zip(resolved) {
+        let requested_type = network
+            .typ
+            .map(|typ| typ.to_string())
+            .unwrap_or("auto".to_string());
+
+        match resolved {
+            Ok(resolved) => table.add_row(Row::new(vec![
+                TableCell::new(location),
+                TableCell::new(&network.name),
+                TableCell::new(requested_type),
+                TableCell::new(resolved.typ),
+                TableCell::new(resolved.intern_ip),
+            ])),
+            Err(err) => {
+                failed = true;
+                table.add_row(Row::new(vec![
+                    TableCell::new(location),
+                    TableCell::new(&network.name),
+                    TableCell::new(requested_type),
+                    TableCell::new(error_style.apply_to(err)),
+                    TableCell::new("-"),
+                ]))
+            }
+        }
+    }
+
+    println!("{}", table.render());
+
+    if failed {
+        return Err(anyhow::anyhow!("Not all networks could be resolved"));
+    }
+
+    Ok(())
+}
+
+async fn firewall(args: crate::cli::Firewall) -> anyhow::Result<()> {
+    let processor = load_processor(&args.target)?;
+
+    let stop = show_spinner("Computing the service groups")?;
+    let specs = processor.service_group_specs().await;
+    stop();
+    let specs = specs?;
+    let rules = crate::firewall::allow_matrix(&specs);
+
+    match args.format {
+        crate::cli::FirewallFormat::Table => {
+            let header_style = console::Style::new().bold();
+            let mut table = term_table::Table::new();
+            table.add_row(Row::new(vec![
+                TableCell::new(header_style.apply_to("from")),
+                TableCell::new(header_style.apply_to("to")),
+                TableCell::new(header_style.apply_to("ports")),
+                TableCell::new(header_style.apply_to("via")),
+            ]));
+
+            for rule in rules {
+                table.add_row(Row::new(vec![
+                    TableCell::new(rule.from),
+                    TableCell::new(rule.to),
+                    TableCell::new(
+                        rule.ports
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ),
+                    TableCell::new(rule.via),
+                ]));
+            }
+
+            println!("{}", table.render());
+        }
+        crate::cli::FirewallFormat::Dot => {
+            println!(
+                "{}",
+                crate::firewall::allow_matrix_graph(&specs, &rules).to_dot()
+            );
+        }
+        crate::cli::FirewallFormat::Mermaid => {
+            println!(
+                "{}",
+                crate::firewall::allow_matrix_graph(&specs, &rules).to_mermaid()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn lint(args: crate::cli::Lint) -> anyhow::Result<()> {
+    let processor = load_processor(&args.target)?;
+    let findings = processor.lint_firewall()?;
+
+    if findings.is_empty() {
+        println!("No findings");
+    }
+
+    show_lint_findings(&findings)
+}
+
+async fn graph(args: crate::cli::Graph) -> anyhow::Result<()> {
+    let processor = load_processor(&args.target)?;
+    let graph = processor.plan_graph().await?;
+
+    match args.format {
+        crate::cli::GraphFormat::Dot => println!("{}", graph.to_dot()),
+        crate::cli::GraphFormat::Mermaid => println!("{}", graph.to_mermaid()),
+        crate::cli::GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+    }
+
+    Ok(())
+}
+
+async fn set_state(args: crate::cli::StateSet) -> anyhow::Result<()> {
+    let crate::cli::PlanTarget {
         project,
         subproject,
         environment,
         ..
-    } = args.clone();
-    let plan: ServicePlan = serde_yml::from_reader(std::fs::File::open(args.plan)?)?;
-    let mut processor = ServicePlanProcessor::new(plan);
+    } = args.target.clone();
+    let processor = load_processor(&args.target)?;
+    let planned = processor.planned_vms(args.service.as_deref())?;
+
+    let stop = show_spinner("Loading the current states")?;
+    let objects = adminapi::query::Query::builder()
+        .filter("servertype", "vm".to_string())
+        .filter("project", project)
+        .filter("subproject", subproject)
+        .filter("environment", environment)
+        .restrict(["hostname", "state"])
+        .build()
+        .request()
+        .await;
     stop();
 
-    let stop = show_spinner("Planning the service landscape")?;
-    processor
-        .project(args.project)
-        .subproject(args.subproject)
-        .environment(args.environment);
-    let objects = processor.get_unrelational_resources().await?;
-    stop();
+    let mut objects = objects?
+        .all()
+        .into_iter()
+        .filter(|object| {
+            planned
+                .iter()
+                .any(|vm| object.get("hostname").as_str() == Some(vm.hostname.as_str()))
+        })
+        .collect::<Vec<_>>();
+    objects.sort_by_key(|object| object.get("hostname").as_str().map(String::from));
 
     let header_style = console::Style::new().bold();
+    let error_style = console::Style::new().red();
     let mut table = term_table::Table::new();
     table.add_row(Row::new(vec![
         TableCell::new(header_style.apply_to("hostname")),
-        TableCell::new(header_style.apply_to("servertype")),
+        TableCell::new(header_style.apply_to("state")),
         TableCell::new(header_style.apply_to("Action")),
     ]));
 
-    println!("\n\nThis action will create the following objects:\n");
-    for object in &objects {
-        let serde_json::Value::String(servertype) = object.get("servertype") else {
-            continue;
-        };
-        let serde_json::Value::String(hostname) = object.get("hostname") else {
-            continue;
+    let mut invalid = 0;
+    let mut changed = Vec::new();
+    for mut object in objects {
+        let hostname = object
+            .get("hostname")
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let current = object.get("state").as_str().unwrap_or_default().to_string();
+
+        let action = match current.parse::<ObjectState>() {
+            Ok(current) if current == args.state => TableCell::new("No action"),
+            // VMs without a state have not been brought into service yet and may start in any
+            parsed
+                if current.is_empty()
+                    || parsed
+                        .as_ref()
+                        .is_ok_and(|current| current.can_transition(args.state)) =>
+            {
+                object.set("state", args.state.to_string())?;
+                changed.push(object);
+
+                let current = if current.is_empty() {
+                    "unset"
+                } else {
+                    &current
+                };
+                TableCell::new(format!("{current} -> {}", args.state))
+            }
+            _ => {
+                invalid += 1;
+
+                TableCell::new(error_style.apply_to(format!("can not move to {}", args.state)))
+            }
         };
 
         table.add_row(Row::new(vec![
             TableCell::new(hostname),
-            TableCell::new(servertype),
-            if object.is_new() {
-                TableCell::new("Create")
-            } else if object.has_changes() {
-                TableCell::new("Update")
-            } else {
-                TableCell::new("No action")
-            },
+            TableCell::new(current),
+            action,
         ]));
     }
 
-    table.add_row(Row::new(vec![
-        TableCell::new(header_style.apply_to("Total")),
-        TableCell::new(header_style.apply_to(objects.len())),
-    ]));
-
     println!("{}", table.render());
 
-    show_unmanaged_objects(
-        objects
-            .iter()
-            .map(|obj| {
-                obj.get("hostname")
-                    .as_str()
-                    .map(ToString::to_string)
-                    .unwrap_or_default()
-            })
-            .collect::<Vec<_>>(),
-        project,
-        subproject,
-        environment,
-    )
-    .await?;
+    let declared = planned
+        .iter()
+        .filter_map(|vm| Some((vm.function.as_str(), vm.state?)))
+        .filter(|(_, state)| *state != args.state)
+        .collect::<std::collections::BTreeMap<_, _>>();
+    for (function, state) in declared {
+        println!(
+            "{}",
+            console::Style::new().yellow().apply_to(format!(
+                "The plan declares the state {state} for {function}, applying it moves the VMs back"
+            ))
+        );
+    }
 
-    if !objects.iter().any(|obj| obj.is_new() || obj.has_changes()) {
+    if invalid > 0 {
+        return Err(anyhow::anyhow!(
+            "{invalid} VMs can not be moved to {}",
+            args.state
+        ));
+    }
+
+    if changed.is_empty() {
         println!("No pending changes");
 
         return Ok(());
@@ -159,13 +658,26 @@ async fn apply(args: crate::cli::Apply) -> anyhow::Result<()> {
         return Ok(());
     }
 
-    println!();
+    futures::future::try_join_all(changed.iter_mut().map(|object| object.commit())).await?;
 
-    let objects = objects
-        .into_iter()
-        .filter(|obj| obj.is_new() || obj.has_changes())
-        .collect::<Vec<_>>();
+    println!("\n\nDone. Moved {} VMs to {}", changed.len(), args.state);
+
+    Ok(())
+}
+
+fn confirm_next_batch() -> anyhow::Result<bool> {
+    let select = dialoguer::Select::new()
+        .with_prompt("Continue with the next batch")
+        .item("No")
+        .item("Yes")
+        .default(0);
 
+    Ok(select.interact()? == 1)
+}
+
+/// Creates or updates the objects and then saves their relations
+async fn commit_objects(objects: Vec<NewObject>, hooks: &HookRunner) -> anyhow::Result<()> {
+    let described = objects.iter().map(HookObject::describe).collect::<Vec<_>>();
     let progress_style =
         ProgressStyle::with_template("{msg:.white.bold} [{wide_bar:.yellow}] {pos}/{len}")?
             .progress_chars("#>=");
@@ -213,6 +725,199 @@ async fn apply(args: crate::cli::Apply) -> anyhow::Result<()> {
     progress.set_style(progress_done_style);
     progress.finish();
 
+    hooks.post_object_create(&described).await;
+
+    Ok(())
+}
+
+async fn apply(args: crate::cli::Apply) -> anyhow::Result<()> {
+    let _lock = args
+        .lock_file
+        .as_deref()
+        .map(PlanLock::acquire)
+        .transpose()?;
+    let crate::cli::PlanTarget {
+        project,
+        subproject,
+        environment,
+        ..
+    } = args.target.clone();
+    let mut processor = load_processor(&args.target)?;
+    processor.adopt(args.adopt);
+
+    let lint = show_lint_findings(&processor.lint_firewall()?);
+    if !args.ignore_lint {
+        lint?;
+    }
+
+    let stop = show_spinner("Planning the service landscape")?;
+    let objects = processor.get_unrelational_resources().await?;
+    stop();
+
+    show_network_usage(&processor.network_usage(), args.warn_utilization)?;
+
+    let stop = show_spinner("Checking the hypervisor capacity")?;
+    let capacity = processor.hypervisor_capacity().await;
+    stop();
+    show_hypervisor_capacity(&capacity?, &processor.unchecked_vms())?;
+
+    let header_style = console::Style::new().bold();
+    let mut table = term_table::Table::new();
+    table.add_row(Row::new(vec![
+        TableCell::new(header_style.apply_to("hostname")),
+        TableCell::new(header_style.apply_to("servertype")),
+        TableCell::new(header_style.apply_to("Action")),
+    ]));
+
+    println!("\n\nThis action will create the following objects:\n");
+    for object in &objects {
+        let serde_json::Value::String(servertype) = object.get("servertype") else {
+            continue;
+        };
+        let serde_json::Value::String(hostname) = object.get("hostname") else {
+            continue;
+        };
+
+        table.add_row(Row::new(vec![
+            TableCell::new(hostname),
+            TableCell::new(servertype),
+            if object.is_new() {
+                TableCell::new("Create")
+            } else if object.has_changes() {
+                TableCell::new("Update")
+            } else {
+                TableCell::new("No action")
+            },
+        ]));
+    }
+
+    table.add_row(Row::new(vec![
+        TableCell::new(header_style.apply_to("Total")),
+        TableCell::new(header_style.apply_to(objects.len())),
+    ]));
+
+    println!("{}", table.render());
+
+    show_planned_changes(&processor.planned_changes());
+
+    show_unmanaged_objects(
+        objects
+            .iter()
+            .map(|obj| {
+                obj.get("hostname")
+                    .as_str()
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>(),
+        processor.ownership()?,
+        project,
+        subproject,
+        environment,
+    )
+    .await?;
+
+    if !objects.iter().any(|obj| obj.is_new() || obj.has_changes()) {
+        println!("No pending changes");
+
+        return Ok(());
+    }
+
+    let select = dialoguer::Select::new()
+        .with_prompt("Continue")
+        .item("No")
+        .item("Yes")
+        .default(0);
+
+    if select.interact()? == 0 {
+        println!("Aborting.");
+
+        return Ok(());
+    }
+
+    println!();
+
+    let mut objects = objects
+        .into_iter()
+        .filter(|obj| obj.is_new() || obj.has_changes())
+        .collect::<Vec<_>>();
+
+    let stop = show_spinner("Re-checking the allocated IPs")?;
+    let reassignments = processor.verify_ip_allocations(&mut objects).await;
+    stop();
+    show_ip_reassignments(&reassignments?);
+
+    let hooks = HookRunner::new(
+        processor.hooks().clone(),
+        args.target.project.clone(),
+        args.target.subproject.clone(),
+        args.target.environment.clone(),
+    );
+    hooks
+        .pre_apply(&objects.iter().map(HookObject::describe).collect::<Vec<_>>())
+        .await
+        .context("Aborting before committing anything")?;
+
+    let batches = processor.rollout_batches(
+        objects,
+        args.batch_size.map(NonZeroUsize::get),
+        args.by_zone,
+    );
+    let total = batches.len();
+    let mut applied = Vec::new();
+
+    for (index, batch) in batches.into_iter().enumerate() {
+        if total > 1 {
+            if index > 0 {
+                let proceed = match args.pause {
+                    // Ctrl+C is caught, so the abort is reported and the lock file is removed
+                    Some(pause) => {
+                        println!("Waiting {pause}s before the next batch, Ctrl+C aborts the rest");
+                        tokio::select! {
+                            _ = tokio::time::sleep(Duration::from_secs(pause)) => true,
+                            signal = tokio::signal::ctrl_c() => {
+                                signal?;
+
+                                false
+                            }
+                        }
+                    }
+                    None => confirm_next_batch()?,
+                };
+
+                if !proceed {
+                    println!(
+                        "Aborting, {} of {total} batches were not applied.",
+                        total - index
+                    );
+                    hooks.post_apply(&applied).await;
+
+                    return Ok(());
+                }
+            }
+
+            let hostnames = batch
+                .iter()
+                .filter_map(|object| object.get("hostname").as_str().map(String::from))
+                .collect::<Vec<_>>();
+            println!("\nBatch {}/{total}: {}", index + 1, hostnames.join(", "));
+        }
+
+        let described = batch.iter().map(HookObject::describe).collect::<Vec<_>>();
+        if let Err(err) = commit_objects(batch, &hooks).await {
+            // The post-apply hooks still learn about the batches, which went through
+            hooks.post_apply(&applied).await;
+
+            return Err(err.context(format!(
+                "Batch {}/{total} failed, the remaining batches were not applied",
+                index + 1
+            )));
+        }
+        applied.extend(described);
+    }
+
+    hooks.post_apply(&applied).await;
+
     println!("\n\nDone. Enjoy your system!");
 
     Ok(())
@@ -226,5 +931,10 @@ pub async fn main() -> anyhow::Result<()> {
 
     match args.subcommand {
         cli::Subcommands::Apply(args) => apply(args).await,
+        cli::Subcommands::Networks(args) => networks(args).await,
+        cli::Subcommands::Firewall(args) => firewall(args).await,
+        cli::Subcommands::Graph(args) => graph(args).await,
+        cli::Subcommands::Lint(args) => lint(args).await,
+        cli::Subcommands::State(cli::StateCommand::Set(args)) => set_state(args).await,
     }
 }
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+/// A hypervisor, which VMs may be placed on
+#[derive(Clone, Debug)]
+pub struct Hypervisor {
+    pub hostname: String,
+    /// The value of the spread attribute, e.g. the rack
+    pub spread: Option<String>,
+}
+
+/// A replica to place, with the hypervisor it is on already or is pinned to
+#[derive(Clone, Debug)]
+pub struct Replica {
+    pub hostname: String,
+    pub current: Option<String>,
+    pub pinned: Option<String>,
+}
+
+/// Chooses a hypervisor for every replica.
+///
+/// Pinned and already placed replicas keep their hypervisor, unless a pinned replica takes it with
+/// `anti_affinity`. The others go to the spread value and then the hypervisor with the fewest
+/// replicas, never sharing a hypervisor with `anti_affinity`.
+///
+/// This only chooses the hypervisors, existing VMs on another one have to be migrated separately.
+pub fn place(
+    replicas: &[Replica],
+    hypervisors: &[Hypervisor],
+    anti_affinity: bool,
+) -> anyhow::Result<Vec<String>> {
+    let spread_of = |hostname: &str| {
+        hypervisors
+            .iter()
+            .find(|hypervisor| hypervisor.hostname == hostname)
+            .and_then(|hypervisor| hypervisor.spread.clone())
+    };
+    let mut hypervisor_usage = HashMap::<String, usize>::new();
+    let mut spread_usage = HashMap::<Option<String>, usize>::new();
+    let mut placements = vec![None; replicas.len()];
+
+    // Pinned replicas go first, so they displace replicas currently on their hypervisor
+    let pinned = replicas
+        .iter()
+        .enumerate()
+        .filter_map(|(index, replica)| Some((index, replica.pinned.as_ref()?)));
+    let current = replicas
+        .iter()
+        .enumerate()
+        .filter(|(_, replica)| replica.pinned.is_none())
+        .filter_map(|(index, replica)| Some((index, replica.current.as_ref()?)));
+
+    for (index, hostname) in pinned.chain(current) {
+        let replica = &replicas[index];
+
+        if replica.pinned.is_some() && !hypervisors.iter().any(|h| &h.hostname == hostname) {
+            return Err(anyhow::anyhow!(
+                "{} is pinned to {hostname}, which is not one of the selected hypervisors",
+                replica.hostname
+            ));
+        }
+
+        let usage = hypervisor_usage.entry(hostname.clone()).or_default();
+        if anti_affinity && *usage > 0 && replica.pinned.is_some() {
+            return Err(anyhow::anyhow!(
+                "{} is pinned to {hostname}, which already hosts another replica",
+                replica.hostname
+            ));
+        }
+        if anti_affinity && *usage > 0 {
+            // Moved away below, the current hypervisor is taken by another replica
+            continue;
+        }
+
+        *usage += 1;
+        *spread_usage.entry(spread_of(hostname)).or_default() += 1;
+        placements[index] = Some(hostname.clone());
+    }
+
+    for (index, replica) in replicas.iter().enumerate() {
+        if placements[index].is_some() {
+            continue;
+        }
+
+        let candidate = hypervisors
+            .iter()
+            .filter(|hypervisor| {
+                !anti_affinity || !hypervisor_usage.contains_key(&hypervisor.hostname)
+            })
+            .min_by_key(|hypervisor| {
+                (
+                    spread_usage.get(&hypervisor.spread).copied().unwrap_or(0),
+                    hypervisor_usage
+                        .get(&hypervisor.hostname)
+                        .copied()
+                        .unwrap_or(0),
+                    hypervisor.hostname.clone(),
+                )
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No hypervisor is left for {} out of {} candidates",
+                    replica.hostname,
+                    hypervisors.len()
+                )
+            })?;
+
+        *hypervisor_usage
+            .entry(candidate.hostname.clone())
+            .or_default() += 1;
+        *spread_usage.entry(candidate.spread.clone()).or_default() += 1;
+        placements[index] = Some(candidate.hostname.clone());
+    }
+
+    Ok(placements.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hypervisors(hypervisors: &[(&str, &str)]) -> Vec<Hypervisor> {
+        hypervisors
+            .iter()
+            .map(|(hostname, spread)| Hypervisor {
+                hostname: hostname.to_string(),
+                spread: Some(spread.to_string()),
+            })
+            .collect()
+    }
+
+    fn replica(hostname: &str, current: Option<&str>, pinned: Option<&str>) -> Replica {
+        Replica {
+            hostname: hostname.to_string(),
+            current: current.map(ToString::to_string),
+            pinned: pinned.map(ToString::to_string),
+        }
+    }
+
+    fn new_replicas(count: usize) -> Vec<Replica> {
+        (1..=count)
+            .map(|index| replica(&format!("vm{index}"), None, None))
+            .collect()
+    }
+
+    #[test]
+    fn anti_affinity_uses_distinct_hypervisors() {
+        let hypervisors = hypervisors(&[("hv1", "r1"), ("hv2", "r1"), ("hv3", "r2")]);
+
+        let mut placements = place(&new_replicas(3), &hypervisors, true).unwrap();
+        placements.sort();
+
+        assert_eq!(placements, vec!["hv1", "hv2", "hv3"]);
+        assert!(place(&new_replicas(4), &hypervisors, true).is_err());
+    }
+
+    #[test]
+    fn replicas_spread_across_racks() {
+        let hypervisors =
+            hypervisors(&[("hv1", "r1"), ("hv2", "r1"), ("hv3", "r2"), ("hv4", "r2")]);
+
+        let placements = place(&new_replicas(2), &hypervisors, false).unwrap();
+
+        assert_eq!(placements, vec!["hv1", "hv3"]);
+    }
+
+    #[test]
+    fn placed_replicas_stay() {
+        let hypervisors = hypervisors(&[("hv1", "r1"), ("hv2", "r2")]);
+        let replicas = vec![
+            replica("vm1", Some("hv2"), None),
+            replica("vm2", None, None),
+        ];
+
+        assert_eq!(
+            place(&replicas, &hypervisors, true).unwrap(),
+            vec!["hv2", "hv1"]
+        );
+    }
+
+    #[test]
+    fn pinned_replicas_displace_placed_ones() {
+        let hypervisors = hypervisors(&[("hv1", "r1"), ("hv2", "r2")]);
+        let replicas = vec![
+            replica("vm1", Some("hv1"), None),
+            replica("vm2", None, Some("hv1")),
+        ];
+
+        assert_eq!(
+            place(&replicas, &hypervisors, true).unwrap(),
+            vec!["hv2", "hv1"]
+        );
+    }
+
+    #[test]
+    fn pinning_is_checked() {
+        let hypervisors = hypervisors(&[("hv1", "r1"), ("hv2", "r2")]);
+
+        assert!(place(&[replica("vm1", None, Some("hv9"))], &hypervisors, false).is_err());
+        assert!(place(
+            &[
+                replica("vm1", None, Some("hv1")),
+                replica("vm2", None, Some("hv1")),
+            ],
+            &hypervisors,
+            true
+        )
+        .is_err());
+        assert_eq!(
+            place(
+                &[
+                    replica("vm1", None, Some("hv1")),
+                    replica("vm2", None, Some("hv1")),
+                ],
+                &hypervisors,
+                false
+            )
+            .unwrap(),
+            vec!["hv1", "hv1"]
+        );
+    }
+}
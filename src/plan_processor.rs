@@ -1,20 +1,49 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Mutex;
-use std::{collections::HashMap, net::IpAddr};
 
 use adminapi::filter::*;
 use adminapi::new_object::NewObject;
 use adminapi::query::Query;
-use futures::TryFutureExt;
 use ipnet::IpNet;
 
-use crate::config::{ExternalFirewallRule, FirewallExport, Service, ServiceInstance, ServicePlan};
+use crate::config::{
+    ExternalFirewallRule, ExternalPeerRule, FirewallDirection, FirewallExport, HealthCheck,
+    HealthCheckType, NetworkTarget, NetworkType, ObjectState, Placement, PlanHooks, RelationSource,
+    ResourceKind, SelectorAttribute, Service, ServiceInstance, ServicePlan, ServiceResource,
+    ServiceVm, SizeProfile,
+};
+use crate::firewall::{ServiceGroupKind, ServiceGroupSpec};
+use crate::graph::Graph;
+use crate::kinds::ResourceKinds;
+use crate::lint::{duplicate_ports, uncovered_ports, LintFinding};
+use crate::placement::{place, Hypervisor, Replica};
+use crate::port_spec::PortSpec;
+use crate::processable_value::{ProcessableValue, QueryExpectation, ValueQuery};
 
 pub struct FreeIps {
     taken_ips: Vec<String>,
     network: IpNet,
+    initially_taken: usize,
+    allocated: usize,
+    missing: usize,
 }
 
 impl FreeIps {
+    /// Only host addresses of the network count as taken, not the network itself or its subnets
+    pub fn new(mut taken_ips: Vec<String>, network: IpNet) -> Self {
+        taken_ips.retain(|ip| ip.parse::<IpAddr>().is_ok_and(|ip| network.contains(&ip)));
+
+        Self {
+            initially_taken: taken_ips.len(),
+            taken_ips,
+            network,
+            allocated: 0,
+            missing: 0,
+        }
+    }
+
     pub fn get_ip(&mut self) -> Option<IpAddr> {
         let ip = self.network.hosts().find(|addr| {
             !self.taken_ips.contains(&addr.to_string())
@@ -22,29 +51,450 @@ impl FreeIps {
                 && !addr.is_multicast()
                 && !addr.is_unspecified()
                 && !addr.to_string().ends_with("::")
-        })?;
+        });
+
+        let Some(ip) = ip else {
+            self.missing += 1;
+
+            return None;
+        };
 
         self.taken_ips.push(ip.to_string());
+        self.allocated += 1;
 
         Some(ip)
     }
+
+    /// The number of host addresses in the network, saturating for huge IPv6 networks
+    pub fn size(&self) -> u128 {
+        match self.network {
+            IpNet::V4(network) => match network.prefix_len() {
+                32 => 1,
+                31 => 2,
+                prefix_len => (1u128 << (32 - prefix_len)) - 2,
+            },
+            IpNet::V6(network) => match network.prefix_len() {
+                128 => 1,
+                prefix_len => 1u128
+                    .checked_shl(128 - prefix_len as u32)
+                    .map(|size| size - 1)
+                    .unwrap_or(u128::MAX),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NetworkUsage {
+    pub network: String,
+    pub size: u128,
+    pub taken: usize,
+    pub allocated: usize,
+    pub missing: usize,
+}
+
+impl NetworkUsage {
+    pub fn remaining(&self) -> u128 {
+        self.size
+            .saturating_sub(self.taken as u128 + self.allocated as u128)
+    }
+
+    /// The utilization after this plan has been applied in percent
+    pub fn utilization(&self) -> f64 {
+        if self.size == 0 {
+            return 100.0;
+        }
+
+        (self.taken + self.allocated + self.missing) as f64 / self.size as f64 * 100.0
+    }
+}
+
+/// The CPUs, memory and disk of a VM or of hypervisors, as in [`SizeProfile::ATTRIBUTES`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resources {
+    pub num_cpu: u64,
+    pub memory: u64,
+    pub disk_size_gib: u64,
+}
+
+impl Resources {
+    /// Reads the resources from the attributes of an object, missing ones count as zero
+    fn read(get: impl Fn(&str) -> serde_json::Value) -> Self {
+        let value = |attribute| get(attribute).as_u64().unwrap_or(0);
+
+        Self {
+            num_cpu: value("num_cpu"),
+            memory: value("memory"),
+            disk_size_gib: value("disk_size_gib"),
+        }
+    }
+
+    /// The amount of every resource by its attribute
+    pub fn values(&self) -> [(&'static str, u64); 3] {
+        [
+            ("num_cpu", self.num_cpu),
+            ("memory", self.memory),
+            ("disk_size_gib", self.disk_size_gib),
+        ]
+    }
+}
+
+impl std::ops::AddAssign for Resources {
+    fn add_assign(&mut self, other: Self) {
+        self.num_cpu += other.num_cpu;
+        self.memory += other.memory;
+        self.disk_size_gib += other.disk_size_gib;
+    }
+}
+
+/// Whether the capacity is the one of a single hypervisor or of all hypervisors of a group
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CapacityScope {
+    Hypervisor,
+    HypervisorGroup,
+}
+
+impl std::fmt::Display for CapacityScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapacityScope::Hypervisor => f.write_str("hypervisor"),
+            CapacityScope::HypervisorGroup => f.write_str("hypervisor group"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HypervisorCapacity {
+    pub scope: CapacityScope,
+    /// The hostname of the hypervisor or the name of the group
+    pub name: String,
+    pub total: Resources,
+    /// Used by VMs, which are not part of the plan
+    pub used: Resources,
+    pub planned: Resources,
+}
+
+impl HypervisorCapacity {
+    /// The resources, which are requested beyond the capacity
+    pub fn exceeded(&self) -> Vec<&'static str> {
+        self.used
+            .values()
+            .into_iter()
+            .zip(self.planned.values())
+            .zip(self.total.values())
+            .filter(|(((_, used), (_, planned)), (_, total))| used + planned > *total)
+            .map(|(((attribute, _), _), _)| attribute)
+            .collect()
+    }
+}
+
+/// The hypervisor or group a VM draws its resources from, and the VM attribute naming it
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct CapacityTarget {
+    scope: CapacityScope,
+    attribute: String,
+    name: String,
+}
+
+#[derive(Clone, Debug)]
+struct VmDemand {
+    hostname: String,
+    /// None if the VM has neither a placement nor a hypervisor group
+    target: Option<CapacityTarget>,
+    size: Resources,
+}
+
+#[derive(Clone, Debug)]
+struct IpAllocation {
+    hostname: String,
+    network: NetworkTarget,
+    ip: IpAddr,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolvedNetwork {
+    pub typ: NetworkType,
+    pub hostname: String,
+    pub intern_ip: String,
+}
+
+fn network_query(typ: NetworkType, name: &str, project: &str) -> Query {
+    let base_query = Query::builder()
+        .filter("hostname", name.to_string())
+        .restrict(["intern_ip", "hostname"]);
+
+    match typ {
+        NetworkType::Route => base_query
+            .filter("servertype", "route_network")
+            .filter("public_networks", not(empty()))
+            .filter("assigned_to", project.to_string())
+            .build(),
+        NetworkType::Project => base_query
+            .filter("servertype", "project_network")
+            .filter("project", project.to_string())
+            .build(),
+        NetworkType::Public => base_query
+            .filter("servertype", "route_network")
+            .filter("public_networks", empty())
+            .build(),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IpReassignment {
+    pub hostname: String,
+    pub network: String,
+    pub old_ip: IpAddr,
+    pub new_ip: IpAddr,
+    pub holders: Vec<String>,
+}
+
+const MAX_IP_VERIFICATION_ROUNDS: usize = 3;
+
+async fn query_taken_ips(network: &IpNet, hostname: &str) -> anyhow::Result<Vec<String>> {
+    Ok(Query::builder()
+        .filter("intern_ip", contained_only_by(network.to_string()))
+        .filter("hostname", not(hostname.to_string()))
+        .restrict(["intern_ip"])
+        .build()
+        .request()
+        .await
+        .map_err(|err| anyhow::anyhow!("Unable to query taken IPs {err}"))?
+        .all()
+        .into_iter()
+        .map(|object| object.get("intern_ip").as_str().unwrap().to_string())
+        .collect::<Vec<_>>())
+}
+
+/// A declared relation with its sources and targets resolved to hostnames
+struct PlannedRelation {
+    location: String,
+    sources: Vec<String>,
+    attribute: String,
+    targets: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlannedChange {
+    pub hostname: String,
+    pub attribute: String,
+    pub change: String,
+}
+
+/// A VM of the plan together with the state the plan declares for it
+#[derive(Clone, Debug)]
+pub struct PlannedVm {
+    pub hostname: String,
+    pub function: String,
+    pub state: Option<ObjectState>,
+}
+
+fn query_key(query: &ValueQuery, filters: &BTreeMap<String, String>) -> String {
+    format!(
+        "{filters:?} restrict={} expect={:?}",
+        query.restrict, query.expect
+    )
+}
+
+fn render_string(
+    value: &ProcessableValue,
+    variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+    field: &str,
+) -> anyhow::Result<String> {
+    match value.render(variables)? {
+        serde_json::Value::String(value) => Ok(value),
+        _ => Err(anyhow::anyhow!("{field} has to be a string")),
+    }
+}
+
+/// Why the relation attribute of the source objects cannot be declared, if it is managed otherwise
+fn relation_conflict(
+    service: &Service,
+    source: RelationSource,
+    attribute: &str,
+) -> Option<&'static str> {
+    let implicit: &[&str] = match source {
+        RelationSource::Vms => &["service_groups", "loadbalancer"],
+        RelationSource::Loadbalancers => &["service_groups", "health_checks"],
+        RelationSource::ServiceGroups => &["sg_allow_from", "sg_allow_to"],
+        RelationSource::HealthChecks | RelationSource::Resources => &[],
+    };
+    if implicit.contains(&attribute) {
+        return Some("the firewall settings manage it");
+    }
+
+    let declared = match source {
+        RelationSource::Vms => {
+            service.vm.contains_key(attribute)
+                || service.instances.values().any(|instance| {
+                    instance.vm.contains_key(attribute)
+                        || instance
+                            .replicas_vm
+                            .values()
+                            .any(|vm| vm.contains_key(attribute))
+                })
+        }
+        RelationSource::Resources => service
+            .resources
+            .iter()
+            .any(|resource| resource.attributes.contains_key(attribute)),
+        _ => false,
+    };
+
+    declared.then_some("it is declared as attribute, too")
+}
+
+/// Splits objects into rollout batches of their indices.
+///
+/// `zones` holds the zone of every existing VM and None for all other objects, which go into the
+/// first batch. Without `by_zone` the VMs of all zones are cut into batches of `batch_size` in one
+/// sequence, so a batch may span zones on purpose.
+fn batch_indices(
+    zones: &[Option<&str>],
+    batch_size: Option<usize>,
+    by_zone: bool,
+) -> Vec<Vec<usize>> {
+    if batch_size.is_none() && !by_zone {
+        return vec![(0..zones.len()).collect()];
+    }
+
+    let mut batches = vec![Vec::new()];
+    let mut zone_vms = BTreeMap::<&str, Vec<usize>>::new();
+    for (index, zone) in zones.iter().enumerate() {
+        match zone {
+            Some(zone) => zone_vms
+                .entry(if by_zone { zone } else { "" })
+                .or_default()
+                .push(index),
+            None => batches[0].push(index),
+        }
+    }
+
+    for (_, mut vms) in zone_vms {
+        while let Some(size) = batch_size.filter(|size| *size < vms.len()) {
+            let rest = vms.split_off(size);
+            batches.push(vms);
+            vms = rest;
+        }
+        batches.push(vms);
+    }
+    batches.retain(|batch| !batch.is_empty());
+
+    batches
+}
+
+/// A service imported by a firewall rule, given as `[plan#]service`
+struct ImportedService {
+    plan: Option<String>,
+    project: Option<String>,
+    name: String,
+}
+
+impl ImportedService {
+    fn parse(service: &str, project: Option<String>) -> Self {
+        let (plan, name) = match service.split_once('#') {
+            Some((plan, name)) => (Some(plan.to_string()), name.to_string()),
+            None => (None, service.to_string()),
+        };
+
+        Self {
+            plan,
+            project,
+            name,
+        }
+    }
+
+    /// The name prefixed by the project and plan it comes from, if it is not one of this plan
+    fn qualified_name(&self) -> String {
+        [&self.project, &self.plan]
+            .into_iter()
+            .flatten()
+            .chain([&self.name])
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+}
+
+fn display_value(value: &serde_json::Value) -> String {
+    value
+        .as_str()
+        .map(ToString::to_string)
+        .unwrap_or_else(|| value.to_string())
+}
+
+/// The values to remove from and to add to a multi-value attribute.
+///
+/// Managed values point to objects of the plan, which may not exist yet. They are linked by the
+/// deferred relations and only kept out of the removals here.
+fn multi_attribute_changes(
+    current: &[serde_json::Value],
+    values: &[serde_json::Value],
+    managed: &[serde_json::Value],
+    append: bool,
+) -> (Vec<serde_json::Value>, Vec<serde_json::Value>) {
+    let remove = current
+        .iter()
+        .filter(|value| !append && !values.contains(value) && !managed.contains(value))
+        .cloned()
+        .collect();
+    let add = values
+        .iter()
+        .filter(|value| !current.contains(value) && !managed.contains(value))
+        .cloned()
+        .collect();
+
+    (remove, add)
 }
 
 pub struct ServicePlanProcessor {
     plan: ServicePlan,
+    plan_path: Option<PathBuf>,
+    referenced_plans: Mutex<HashMap<String, ServicePlan>>,
     variables: HashMap<String, Box<dyn strfmt::DisplayStr>>,
+    /// The free IPs of every loaded network by its hostname
     network_ips: Mutex<HashMap<String, FreeIps>>,
+    /// The hostname of the network every rendered network reference resolved to
+    network_pools: Mutex<HashMap<String, String>>,
+    ip_allocations: Mutex<Vec<IpAllocation>>,
+    vm_demands: Mutex<Vec<VmDemand>>,
+    /// The zone of every planned VM by hostname
+    vm_zones: Mutex<HashMap<String, String>>,
+    planned_changes: Mutex<Vec<PlannedChange>>,
     project: Option<String>,
     subproject: Option<String>,
     environment: Option<String>,
+    adopt: bool,
+    kinds: ResourceKinds,
+    /// The results of `!query` values by their rendered query
+    query_results: Mutex<HashMap<String, serde_json::Value>>,
+    sizes: HashMap<String, SizeProfile>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ProcessorBuildContext {
     function: String,
+    /// The zone of the objects being built, if they belong to one
+    #[serde(default)]
+    zone: Option<String>,
+    /// The number of the replica being built, starting at 1 like in its hostname
+    #[serde(default)]
+    index: Option<u32>,
+    /// Relations set by the processor itself, which are never removed from declared attributes
+    #[serde(default)]
+    managed_relations: HashMap<String, Vec<serde_json::Value>>,
 }
 
 impl ProcessorBuildContext {
+    pub fn new(function: &str) -> Self {
+        Self {
+            function: function.to_string(),
+            zone: None,
+            index: None,
+            managed_relations: Default::default(),
+        }
+    }
+
     pub fn get_render_variables<'a>(
         &'a self,
         base: &'a HashMap<String, Box<dyn strfmt::DisplayStr>>,
@@ -56,6 +506,12 @@ impl ProcessorBuildContext {
         }
 
         variables.insert("function".to_string(), &self.function);
+        if let Some(zone) = &self.zone {
+            variables.insert("zone".to_string(), zone);
+        }
+        if let Some(index) = &self.index {
+            variables.insert("index".to_string(), index);
+        }
 
         variables
     }
@@ -63,16 +519,54 @@ impl ProcessorBuildContext {
 
 impl ServicePlanProcessor {
     pub fn new(plan: ServicePlan) -> Self {
+        let mut kinds = ResourceKinds::builtin();
+        kinds.extend(&plan.kinds);
+        let sizes = plan.sizes.clone();
+
         Self {
             plan,
+            plan_path: None,
+            referenced_plans: Default::default(),
             variables: Default::default(),
             project: None,
             subproject: None,
             environment: None,
             network_ips: Default::default(),
+            network_pools: Default::default(),
+            ip_allocations: Default::default(),
+            vm_demands: Default::default(),
+            vm_zones: Default::default(),
+            planned_changes: Default::default(),
+            adopt: false,
+            kinds,
+            query_results: Default::default(),
+            sizes,
         }
     }
 
+    /// Size profiles of a profile file, the profiles of the plan itself still take precedence
+    pub fn sizes(&mut self, sizes: HashMap<String, SizeProfile>) -> &mut Self {
+        self.sizes = sizes;
+        self.sizes.extend(self.plan.sizes.clone());
+
+        self
+    }
+
+    /// Kinds of a kinds file, the kinds of the plan itself still take precedence
+    pub fn kinds(&mut self, kinds: &HashMap<String, ResourceKind>) -> &mut Self {
+        self.kinds = ResourceKinds::builtin();
+        self.kinds.extend(kinds).extend(&self.plan.kinds);
+
+        self
+    }
+
+    /// The location of the plan, other plans are referenced relative to it
+    pub fn plan_path(&mut self, plan_path: PathBuf) -> &mut Self {
+        self.plan_path = Some(plan_path);
+
+        self
+    }
+
     pub fn project(&mut self, project: String) -> &mut Self {
         self.project = Some(project.clone());
         self.variables
@@ -95,260 +589,1743 @@ impl ServicePlanProcessor {
         self
     }
 
-    pub async fn get_unrelational_resources(&self) -> anyhow::Result<Vec<NewObject>> {
-        let mut new_objects = Vec::new();
+    /// Lists every network referenced by the plan together with its location in the plan
+    pub fn network_references(&self) -> anyhow::Result<Vec<(String, NetworkTarget)>> {
+        let mut references = Vec::new();
+        let mut services = self.plan.services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(function, _)| *function);
+
+        for (function, service) in services {
+            let context = ProcessorBuildContext::new(function);
+            let variables = context.get_render_variables(&self.variables);
+            let mut instances = service.instances.iter().collect::<Vec<_>>();
+            instances.sort_by_key(|(zone, _)| *zone);
+
+            for (zone, instance) in instances {
+                references.push((
+                    format!("services.{function}.instances.{zone}.project_network"),
+                    instance.project_network.render(&variables)?,
+                ));
+            }
 
-        for (function, service) in &self.plan.services {
-            let mut context = ProcessorBuildContext {
-                function: function.clone(),
-            };
+            for (index, export) in service.firewall.export.iter().enumerate() {
+                if let Some(loadbalancer) = &export.loadbalancer {
+                    references.push((
+                        format!(
+                            "services.{function}.firewall.export[{index}].loadbalancer.public_network"
+                        ),
+                        loadbalancer.public_network.render(&variables)?,
+                    ));
+                }
+            }
 
-            new_objects.extend(
-                self.get_unrelational_resource(service, &mut context)
-                    .await?
-                    .into_iter(),
-            );
+            for (index, external) in service.firewall.external.iter().enumerate() {
+                for (network_index, network) in external.networks.iter().enumerate() {
+                    references.push((
+                        format!(
+                            "services.{function}.firewall.external[{index}].networks[{network_index}]"
+                        ),
+                        network.render(&variables)?,
+                    ));
+                }
+            }
         }
 
-        Ok(new_objects)
+        Ok(references)
     }
 
-    async fn get_unrelational_resource(
-        &self,
-        service: &Service,
-        context: &mut ProcessorBuildContext,
-    ) -> anyhow::Result<Vec<NewObject>> {
-        let mut new_objects = Vec::new();
+    /// Allows taking over objects, which are not owned by this plan
+    pub fn adopt(&mut self, adopt: bool) -> &mut Self {
+        self.adopt = adopt;
 
-        let mut new_vms = self.get_new_vms(service, context).await?;
-        let new_sgs = self.get_new_service_groups(service, context).await?;
-        let render_variables = context.get_render_variables(&self.variables);
-        let new_lbs =
-            service.firewall.export.iter().map(|export| {
-                self.create_loadbalancer(export, &render_variables, &context.function)
-            });
-        let new_lbs = futures::future::try_join_all(new_lbs)
-            .await?
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
+        self
+    }
 
-        for vm in &mut new_vms {
-            vm.deferred(|server| {
-                for sg in &new_sgs {
-                    if let serde_json::Value::String(hostname) = sg.get("hostname") {
-                        server.add("service_groups", hostname)?;
-                    }
-                }
+    pub fn hooks(&self) -> &PlanHooks {
+        &self.plan.hooks
+    }
 
-                for lb in &new_lbs {
-                    if lb.get("servertype").as_str().unwrap_or_default() != "loadbalancer" {
-                        continue;
-                    }
+    /// Returns the ownership attribute and the rendered owner of this plan
+    pub fn ownership(&self) -> anyhow::Result<(String, String)> {
+        let ownership = &self.plan.ownership;
+        let owner = match &ownership.owner {
+            Some(owner) => {
+                let variables = self
+                    .variables
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.as_ref()))
+                    .collect::<HashMap<_, _>>();
+                let serde_json::Value::String(owner) = owner.render(&variables)? else {
+                    return Err(anyhow::anyhow!("ownership.owner has to be a string"));
+                };
 
-                    server.add("loadbalancer", lb.get("hostname"))?;
-                }
+                owner
+            }
+            None => self
+                .plan_path
+                .as_ref()
+                .and_then(|path| path.file_stem())
+                .map(|stem| stem.to_string_lossy().to_string())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("ownership.owner is required for plans not read from a file")
+                })?,
+        };
+
+        Ok((ownership.attribute.clone(), owner))
+    }
+
+    fn claim_ownership(&self, object: &mut NewObject, hostname: &str) -> anyhow::Result<()> {
+        let (attribute, owner) = self.ownership()?;
+        let current_owner = object.get(&attribute);
+        let current_owner = current_owner.as_str().unwrap_or_default();
 
-                anyhow::Ok(())
-            })?;
+        if current_owner == owner {
+            return Ok(());
         }
 
-        new_objects.extend(new_vms);
-        new_objects.extend(new_sgs);
-        new_objects.extend(new_lbs);
+        if !object.is_new() {
+            if !self.adopt {
+                return Err(match current_owner {
+                    "" => anyhow::anyhow!(
+                        "{hostname} already exists, but is not managed by any plan. Use --adopt to take it over"
+                    ),
+                    current_owner => anyhow::anyhow!(
+                        "{hostname} is managed by the plan {current_owner:?}. Use --adopt to take it over"
+                    ),
+                });
+            }
 
-        Ok(new_objects)
+            self.record_change(
+                hostname,
+                &attribute,
+                format!("adopt from {current_owner:?}"),
+            );
+        }
+
+        object.set(&attribute, owner)?;
+
+        Ok(())
     }
 
-    async fn get_new_vms(
-        &self,
-        service: &Service,
-        context: &mut ProcessorBuildContext,
-    ) -> anyhow::Result<Vec<NewObject>> {
-        let mut vms = Vec::new();
+    pub async fn get_unrelational_resources(&self) -> anyhow::Result<Vec<NewObject>> {
+        let mut new_objects = Vec::new();
+        let mut relations = Vec::new();
 
-        for (zone, instance) in &service.instances {
-            vms.extend(
-                self.generate_vms_for_network_zone(zone, instance, context, service)
-                    .await?,
-            );
+        for (function, service) in &self.plan.services {
+            let mut context = ProcessorBuildContext::new(function);
+
+            let (objects, service_relations) = self
+                .get_unrelational_resource(service, &mut context)
+                .await?;
+            new_objects.extend(objects);
+            relations.extend(service_relations);
         }
 
-        Ok(vms)
+        self.apply_relations(&mut new_objects, relations).await?;
+
+        Ok(new_objects)
     }
 
-    async fn generate_vms_for_network_zone(
+    /// Makes the relation attributes of the source objects point to exactly the declared targets,
+    /// once every target is known to exist
+    async fn apply_relations(
         &self,
-        zone: &str,
-        instance: &ServiceInstance,
-        context: &mut ProcessorBuildContext,
-        service: &Service,
-    ) -> anyhow::Result<Vec<NewObject>> {
-        let variables = context.get_render_variables(&self.variables);
-        let serde_json::Value::String(network_name) =
-            instance.project_network.render(&variables)?
-        else {
-            return Err(anyhow::anyhow!("The project network has to be a string!"));
-        };
-
-        let mut vms = Vec::new();
+        new_objects: &mut [NewObject],
+        relations: Vec<PlannedRelation>,
+    ) -> anyhow::Result<()> {
+        let mut existing = new_objects
+            .iter()
+            .filter_map(|object| object.get("hostname").as_str().map(ToString::to_string))
+            .collect::<HashSet<_>>();
+
+        for relation in &relations {
+            for target in &relation.targets {
+                if existing.contains(target) {
+                    continue;
+                }
 
-        for instance in 0..instance.replicas {
-            let mut hostname = format!("{zone}-");
+                if !self.object_exists(None, target).await? {
+                    return Err(anyhow::anyhow!(
+                        "The target {target} of {} does not exist",
+                        relation.location
+                    ));
+                }
 
-            if let Some(subproject) = &self.subproject {
-                hostname.push_str(subproject);
-                hostname.push('-');
+                existing.insert(target.clone());
             }
+        }
 
-            if let Some(environment) = &self.environment {
-                if environment.ne("production") {
-                    hostname.push_str(environment);
-                    hostname.push('-');
+        // Relations declaring the same attribute of a source add up
+        let mut declared = HashMap::<(&str, &str), Vec<serde_json::Value>>::new();
+        for relation in &relations {
+            for source in &relation.sources {
+                let targets = declared
+                    .entry((source.as_str(), relation.attribute.as_str()))
+                    .or_default();
+
+                for target in &relation.targets {
+                    let target = serde_json::Value::String(target.clone());
+                    if !targets.contains(&target) {
+                        targets.push(target);
+                    }
                 }
             }
+        }
 
-            hostname.push_str(&context.function);
-            hostname.push_str(
-                format!(
-                    "{:02}.{}.ig.local",
-                    instance + 1,
-                    self.project.as_ref().cloned().unwrap_or_default()
-                )
-                .as_str(),
-            );
+        for object in new_objects.iter_mut() {
+            let serde_json::Value::String(hostname) = object.get("hostname") else {
+                continue;
+            };
+            let mut attributes = declared
+                .iter()
+                .filter(|((source, _), _)| *source == hostname)
+                .map(|((_, attribute), targets)| (*attribute, targets.clone()))
+                .collect::<Vec<_>>();
+            attributes.sort_by_key(|(attribute, _)| *attribute);
+
+            for (attribute, targets) in attributes {
+                match object.get(attribute) {
+                    serde_json::Value::Array(current) => {
+                        for value in current.iter().filter(|value| !targets.contains(value)) {
+                            self.record_change(
+                                &hostname,
+                                attribute,
+                                format!("remove {}", display_value(value)),
+                            );
+                        }
+                    }
+                    serde_json::Value::Null => {}
+                    _ if targets.len() > 1 => {
+                        return Err(anyhow::anyhow!(
+                            "{attribute} of {hostname} takes a single value, but {} targets are declared",
+                            targets.len()
+                        ));
+                    }
+                    current if !targets.contains(&current) => {
+                        self.record_change(
+                            &hostname,
+                            attribute,
+                            format!("replace {}", display_value(&current)),
+                        );
+                    }
+                    _ => {}
+                }
 
-            let mut vm = self
-                .create_vm_base_object(&hostname, context, service)
-                .await?
-                .clone();
-            if vm.get("intern_ip").is_null() {
-                vm.set(
-                    "intern_ip",
-                    self.get_free_ip(&network_name).await?.to_string(),
-                )?;
-            }
+                object.deferred(|server| {
+                    match server.get(attribute) {
+                        serde_json::Value::Array(current) => {
+                            for value in current.iter().filter(|value| !targets.contains(value)) {
+                                server.remove(attribute, value.clone())?;
+                            }
+                            for target in targets.iter().filter(|target| !current.contains(target))
+                            {
+                                server.add(attribute, target.clone())?;
+                            }
+                        }
+                        _ => match targets.as_slice() {
+                            [target] => {
+                                server.set(attribute, target.clone())?;
+                            }
+                            _ => {
+                                return Err(anyhow::anyhow!(
+                                    "{attribute} of {hostname} takes a single value, but {} targets are declared",
+                                    targets.len()
+                                ));
+                            }
+                        },
+                    }
 
-            vms.push(vm);
+                    anyhow::Ok(())
+                })?;
+            }
         }
 
-        Ok(vms)
+        Ok(())
     }
 
-    async fn create_vm_base_object(
+    /// Resolves the targets of the service's relations. Query targets have to match any object.
+    async fn plan_relations(
         &self,
-        hostname: &str,
-        context: &ProcessorBuildContext,
         service: &Service,
-    ) -> anyhow::Result<NewObject> {
-        let mut new_object = NewObject::get_or_create("vm", hostname).await?;
-        new_object.set("hostname", hostname.to_string())?;
-        let context_variables = context.get_render_variables(&self.variables);
+        context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        sources: &HashMap<RelationSource, Vec<String>>,
+        function: &str,
+    ) -> anyhow::Result<Vec<PlannedRelation>> {
+        let mut relations = Vec::new();
+
+        for (index, relation) in service.relations.iter().enumerate() {
+            let location = format!("services.{function}.relations.[{index}]");
+            if let Some(reason) = relation_conflict(service, relation.from, &relation.attribute) {
+                return Err(anyhow::anyhow!(
+                    "{location} cannot manage {} of the {}, as {reason}",
+                    relation.attribute,
+                    relation.from
+                ));
+            }
+            let mut targets = relation
+                .to
+                .hostnames
+                .iter()
+                .map(|hostname| render_string(hostname, context_variables, &location))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            if !relation.to.query.is_empty() {
+                let mut query = Query::builder();
+                for (attribute, value) in &relation.to.query {
+                    query = query.filter(
+                        attribute,
+                        render_string(value, context_variables, &location)?,
+                    );
+                }
 
-        for (key, value) in &service.vm {
-            let value = value.render(&context_variables)?;
-            if let serde_json::Value::Array(values) = value {
-                for value in values {
-                    new_object.add(key, value)?;
+                let found = query
+                    .restrict(["hostname"])
+                    .build()
+                    .request()
+                    .await
+                    .map_err(|err| {
+                        anyhow::anyhow!("Unable to query the targets of {location}: {err}")
+                    })?
+                    .all()
+                    .into_iter()
+                    .filter_map(|object| object.get("hostname").as_str().map(ToString::to_string))
+                    .collect::<Vec<_>>();
+
+                if found.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "The query of {location} does not match any object"
+                    ));
                 }
 
-                continue;
+                targets.extend(found);
             }
-            new_object.set(key, value)?;
-        }
 
-        if let Some(value) = &self.project {
-            new_object.set("project", value.clone())?;
-        }
-        if let Some(value) = &self.subproject {
-            new_object.set("subproject", value.clone())?;
-        }
-        if let Some(value) = &self.environment {
-            new_object.set("environment", value.clone())?;
+            relations.push(PlannedRelation {
+                location,
+                sources: sources.get(&relation.from).cloned().unwrap_or_default(),
+                attribute: relation.attribute.clone(),
+                targets,
+            });
         }
 
-        new_object.set("function", context.function.clone())?;
-
-        Ok(new_object)
+        Ok(relations)
     }
 
-    async fn get_free_ip(&self, network_name: &str) -> anyhow::Result<IpAddr> {
-        if let Some(ips) = self.network_ips.lock().unwrap().get_mut(network_name) {
-            return ips
-                .get_ip()
-                .ok_or(anyhow::anyhow!("No free IPs in network {network_name}"));
-        }
+    async fn get_unrelational_resource(
+        &self,
+        service: &Service,
+        context: &mut ProcessorBuildContext,
+    ) -> anyhow::Result<(Vec<NewObject>, Vec<PlannedRelation>)> {
+        let mut new_objects = Vec::new();
 
-        let base_query = Query::builder()
-            .filter("hostname", network_name.to_string())
-            .restrict(["intern_ip", "hostname"]);
+        let new_sgs = self.get_new_service_groups(service, context).await?;
+        let (loadbalancers, new_lbs) = self.get_new_loadbalancers(service, context).await?;
+        let sg_hostnames = new_sgs
+            .iter()
+            .map(|sg| sg.get("hostname"))
+            .collect::<Vec<_>>();
 
-        let rn_query = base_query
-            .clone()
-            .filter("servertype", "route_network")
-            .filter("public_networks", not(empty()))
-            .filter(
-                "assigned_to",
-                self.project.as_ref().cloned().unwrap_or_default(),
-            )
-            .build();
-        let pn_query = base_query
-            .filter("servertype", "project_network")
-            .filter(
-                "project",
-                self.project.as_ref().cloned().unwrap_or_default(),
+        let mut instances = service.instances.iter().collect::<Vec<_>>();
+        instances.sort_by_key(|(zone, _)| *zone);
+
+        let mut vms = Vec::new();
+        for (zone, instance) in instances {
+            let zone_lbs = loadbalancers
+                .iter()
+                .filter(|(lb_zone, _)| lb_zone.as_ref().is_none_or(|lb_zone| lb_zone == zone))
+                .map(|(_, hostname)| hostname.clone())
+                .collect::<Vec<_>>();
+
+            context.zone = Some(zone.clone());
+            context.managed_relations = HashMap::from([
+                ("service_groups".to_string(), sg_hostnames.clone()),
+                ("loadbalancer".to_string(), zone_lbs.clone()),
+            ]);
+            let mut new_vms = self
+                .generate_vms_for_network_zone(zone, instance, context, service)
+                .await?;
+
+            for vm in &mut new_vms {
+                vm.deferred(|server| {
+                    for sg in &sg_hostnames {
+                        server.add("service_groups", sg.clone())?;
+                    }
+
+                    for lb in &zone_lbs {
+                        server.add("loadbalancer", lb.clone())?;
+                    }
+
+                    anyhow::Ok(())
+                })?;
+            }
+
+            vms.extend(new_vms);
+        }
+        context.zone = None;
+
+        let render_variables = context.get_render_variables(&self.variables);
+        let (new_resources, resource_relations): (Vec<_>, Vec<_>) =
+            futures::future::try_join_all(service.resources.iter().map(|resource| {
+                self.create_resource(resource, &render_variables, &context.function)
+            }))
+            .await?
+            .into_iter()
+            .unzip();
+
+        let hostnames = |objects: &[NewObject]| {
+            objects
+                .iter()
+                .filter_map(|object| object.get("hostname").as_str().map(ToString::to_string))
+                .collect::<Vec<_>>()
+        };
+        let hc_servertype = self.kinds.get("health_check").servertype;
+        let sources = HashMap::from([
+            (RelationSource::Vms, hostnames(&vms)),
+            (RelationSource::ServiceGroups, hostnames(&new_sgs)),
+            (
+                RelationSource::Loadbalancers,
+                loadbalancers
+                    .iter()
+                    .filter_map(|(_, hostname)| hostname.as_str().map(ToString::to_string))
+                    .collect(),
+            ),
+            (
+                RelationSource::HealthChecks,
+                hostnames(
+                    &new_lbs
+                        .iter()
+                        .filter(|object| object.get("servertype").as_str() == Some(&hc_servertype))
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+            (RelationSource::Resources, hostnames(&new_resources)),
+        ]);
+        let mut relations = self
+            .plan_relations(service, &render_variables, &sources, &context.function)
+            .await?;
+        relations.extend(resource_relations.into_iter().flatten());
+
+        new_objects.extend(vms);
+        new_objects.extend(new_sgs);
+        new_objects.extend(new_lbs);
+        new_objects.extend(new_resources);
+
+        Ok((new_objects, relations))
+    }
+
+    /// Returns the zone and hostname of every loadbalancer together with the new objects.
+    ///
+    /// Loadbalancers with `per_zone` are created once for every zone of the service.
+    async fn get_new_loadbalancers(
+        &self,
+        service: &Service,
+        context: &ProcessorBuildContext,
+    ) -> anyhow::Result<(Vec<(Option<String>, serde_json::Value)>, Vec<NewObject>)> {
+        let mut zones = service.instances.keys().collect::<Vec<_>>();
+        zones.sort();
+        let zone_contexts = zones
+            .iter()
+            .map(|zone| ProcessorBuildContext {
+                zone: Some(zone.to_string()),
+                ..ProcessorBuildContext::new(&context.function)
+            })
+            .collect::<Vec<_>>();
+        let zone_variables = zone_contexts
+            .iter()
+            .map(|context| context.get_render_variables(&self.variables))
+            .collect::<Vec<_>>();
+        let render_variables = context.get_render_variables(&self.variables);
+
+        let mut new_lbs = Vec::new();
+        for export in &service.firewall.export {
+            let Some(loadbalancer) = &export.loadbalancer else {
+                continue;
+            };
+
+            if loadbalancer.per_zone {
+                for (zone, variables) in zones.iter().zip(&zone_variables) {
+                    new_lbs.push((
+                        Some(zone.to_string()),
+                        self.create_loadbalancer(export, variables, &context.function),
+                    ));
+                }
+            } else {
+                new_lbs.push((
+                    None,
+                    self.create_loadbalancer(export, &render_variables, &context.function),
+                ));
+            }
+        }
+
+        let created = futures::future::try_join_all(
+            new_lbs
+                .into_iter()
+                .map(|(zone, objects)| async move { anyhow::Ok((zone, objects.await?)) }),
+        )
+        .await?;
+
+        let mut loadbalancers = Vec::new();
+        let mut new_objects: Vec<NewObject> = Vec::new();
+        for (zone, objects) in created {
+            for object in objects {
+                let hostname = object.get("hostname");
+
+                if object.get("servertype").as_str() == Some("loadbalancer") {
+                    if loadbalancers.iter().any(|(_, lb)| lb == &hostname) {
+                        return Err(anyhow::anyhow!(
+                            "The loadbalancer {hostname} is declared more than once, per_zone loadbalancers need {{zone}} in their name"
+                        ));
+                    }
+
+                    loadbalancers.push((zone.clone(), hostname));
+                } else if new_objects
+                    .iter()
+                    .any(|new| new.get("hostname") == hostname)
+                {
+                    // Health checks shared by the loadbalancers of all zones
+                    continue;
+                }
+
+                new_objects.push(object);
+            }
+        }
+
+        Ok((loadbalancers, new_objects))
+    }
+
+    async fn generate_vms_for_network_zone(
+        &self,
+        zone: &str,
+        instance: &ServiceInstance,
+        context: &mut ProcessorBuildContext,
+        service: &Service,
+    ) -> anyhow::Result<Vec<NewObject>> {
+        let variables = context.get_render_variables(&self.variables);
+        let network = instance.project_network.render(&variables)?;
+
+        self.load_network(&network).await?;
+
+        if let Some(index) = instance
+            .replicas_vm
+            .keys()
+            .find(|index| **index == 0 || **index > instance.replicas)
+        {
+            return Err(anyhow::anyhow!(
+                "services.{}.instances.{zone}.replicas_vm.{index} does not match any of the {} replicas",
+                context.function,
+                instance.replicas
+            ));
+        }
+
+        let mut vms = Vec::new();
+
+        let size = instance.size.as_ref().or(service.size.as_ref());
+        let size_attributes = match size {
+            Some(size) => match self.sizes.get(size) {
+                Some(profile) => profile.attributes(),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "The size profile {size:?} of services.{}.instances.{zone} does not exist",
+                        context.function
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        for replica in 0..instance.replicas {
+            let hostname = self.vm_hostname(zone, replica, &context.function);
+            let mut attributes = size_attributes.clone();
+            attributes.extend(service.vm.clone());
+            attributes.extend(instance.vm.clone());
+            if let Some(overrides) = instance.replicas_vm.get(&(replica + 1)) {
+                attributes.extend(overrides.clone());
+            }
+
+            context.index = Some(replica + 1);
+            let vm = self
+                .create_vm_base_object(&hostname, context, &attributes)
+                .await;
+            context.index = None;
+
+            let mut vm = vm?;
+            if let Some(state) = instance.state.or(service.state) {
+                self.apply_state(&mut vm, &hostname, state)?;
+            }
+
+            if vm.get("intern_ip").is_null() {
+                if let Some(ip) = self.get_free_ip(&network).await? {
+                    vm.set("intern_ip", ip.to_string())?;
+                    self.record_ip_allocation(&hostname, &network, ip);
+                }
+            }
+
+            self.vm_zones
+                .lock()
+                .unwrap()
+                .insert(hostname, zone.to_string());
+            vms.push(vm);
+        }
+
+        if let Some(placement) = &instance.placement {
+            self.place_vms(zone, placement, &mut vms, context).await?;
+        }
+
+        let group_attribute = self
+            .kinds
+            .get("vm")
+            .attributes
+            .get("hypervisor_group")
+            .cloned();
+        let mut demands = self.vm_demands.lock().unwrap();
+        for vm in &vms {
+            let (scope, attribute) = match (&instance.placement, &group_attribute) {
+                (Some(placement), _) => (CapacityScope::Hypervisor, Some(&placement.attribute)),
+                (None, attribute) => (CapacityScope::HypervisorGroup, attribute.as_ref()),
+            };
+            let target = attribute.and_then(|attribute| {
+                Some(CapacityTarget {
+                    scope,
+                    attribute: attribute.clone(),
+                    name: vm
+                        .get(attribute)
+                        .as_str()
+                        .filter(|name| !name.is_empty())?
+                        .to_string(),
+                })
+            });
+
+            demands.push(VmDemand {
+                hostname: vm.get("hostname").as_str().unwrap_or_default().to_string(),
+                target,
+                size: Resources::read(|attribute| vm.get(attribute)),
+            });
+        }
+        drop(demands);
+
+        Ok(vms)
+    }
+
+    /// Writes the chosen hypervisor of every VM of the zone into the placement attribute.
+    ///
+    /// Only new VMs get a hypervisor written, for existing ones the change only suggests a migration.
+    async fn place_vms(
+        &self,
+        zone: &str,
+        placement: &Placement,
+        vms: &mut [NewObject],
+        context: &ProcessorBuildContext,
+    ) -> anyhow::Result<()> {
+        let location = format!("services.{}.instances.{zone}.placement", context.function);
+        let variables = context.get_render_variables(&self.variables);
+        let mut query = Query::builder().filter("servertype", "hypervisor".to_string());
+        for (attribute, value) in &placement.hypervisors {
+            query = query.filter(attribute, render_string(value, &variables, &location)?);
+        }
+        let mut restrict = vec!["hostname".to_string()];
+        restrict.extend(placement.spread.clone());
+
+        let mut hypervisors = query
+            .restrict(restrict)
+            .build()
+            .request()
+            .await
+            .map_err(|err| anyhow::anyhow!("Unable to query the hypervisors of {location}: {err}"))?
+            .all()
+            .into_iter()
+            .filter_map(|object| {
+                let hostname = object.get("hostname").as_str()?.to_string();
+                let spread = placement
+                    .spread
+                    .as_ref()
+                    .map(|attribute| display_value(&object.get(attribute)));
+
+                Some(Hypervisor { hostname, spread })
+            })
+            .collect::<Vec<_>>();
+        hypervisors.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+
+        if let Some(index) = placement
+            .pinned
+            .keys()
+            .find(|index| **index == 0 || **index as usize > vms.len())
+        {
+            return Err(anyhow::anyhow!(
+                "{location}.pinned.{index} does not match any of the {} replicas",
+                vms.len()
+            ));
+        }
+
+        let replicas = vms
+            .iter()
+            .enumerate()
+            .map(|(index, vm)| Replica {
+                hostname: vm.get("hostname").as_str().unwrap_or_default().to_string(),
+                current: vm
+                    .get(&placement.attribute)
+                    .as_str()
+                    .filter(|current| !current.is_empty())
+                    .map(ToString::to_string),
+                pinned: placement.pinned.get(&(index as u32 + 1)).cloned(),
+            })
+            .collect::<Vec<_>>();
+        let placements = place(&replicas, &hypervisors, placement.anti_affinity)
+            .map_err(|err| anyhow::anyhow!("Unable to satisfy {location}: {err}"))?;
+
+        for ((vm, replica), hypervisor) in vms.iter_mut().zip(&replicas).zip(placements) {
+            if replica.current.as_ref() == Some(&hypervisor) {
+                continue;
+            }
+
+            match &replica.current {
+                Some(current) => self.record_change(
+                    &replica.hostname,
+                    &placement.attribute,
+                    format!("migrate from {current} to {hypervisor} separately"),
+                ),
+                None => {
+                    vm.set(&placement.attribute, hypervisor)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The VMs of all services or of a single one, without looking anything up in Serveradmin
+    pub fn planned_vms(&self, function: Option<&str>) -> anyhow::Result<Vec<PlannedVm>> {
+        if let Some(function) = function {
+            if !self.plan.services.contains_key(function) {
+                return Err(anyhow::anyhow!("The service {function} does not exist"));
+            }
+        }
+
+        let mut vms = Vec::new();
+        for (name, service) in &self.plan.services {
+            if function.is_some_and(|function| function != name) {
+                continue;
+            }
+
+            for (zone, instance) in &service.instances {
+                for replica in 0..instance.replicas {
+                    vms.push(PlannedVm {
+                        hostname: self.vm_hostname(zone, replica, name),
+                        function: name.clone(),
+                        state: instance.state.or(service.state),
+                    });
+                }
+            }
+        }
+        vms.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+
+        Ok(vms)
+    }
+
+    fn vm_hostname(&self, zone: &str, instance: u32, function: &str) -> String {
+        let mut hostname = format!("{zone}-");
+
+        if let Some(subproject) = &self.subproject {
+            hostname.push_str(subproject);
+            hostname.push('-');
+        }
+
+        if let Some(environment) = &self.environment {
+            if environment.ne("production") {
+                hostname.push_str(environment);
+                hostname.push('-');
+            }
+        }
+
+        hostname.push_str(function);
+        hostname.push_str(
+            format!(
+                "{:02}.{}.ig.local",
+                instance + 1,
+                self.project.as_ref().cloned().unwrap_or_default()
             )
-            .build();
-        let pub_query = Query::builder()
-            .filter("hostname", network_name.to_string())
-            .filter("servertype", "route_network")
-            .filter("public_networks", empty())
-            .restrict(["intern_ip", "hostname"])
-            .build();
+            .as_str(),
+        );
 
-        let (route_network, project_network, public_network) = futures::try_join!(
-            rn_query
-                .request()
-                .map_err(|err| anyhow::anyhow!("Unable to query route_network: {err}")),
-            pn_query
-                .request()
-                .map_err(|err| anyhow::anyhow!("Unable to query project_network: {err}")),
-            pub_query
-                .request()
-                .map_err(|err| anyhow::anyhow!("Unable to query public_network: {err}"))
-        )?;
+        hostname
+    }
+
+    async fn create_vm_base_object(
+        &self,
+        hostname: &str,
+        context: &ProcessorBuildContext,
+        attributes: &ServiceVm,
+    ) -> anyhow::Result<NewObject> {
+        let context_variables = context.get_render_variables(&self.variables);
+
+        self.create_base_object(
+            "vm",
+            hostname,
+            &context.function,
+            attributes,
+            &context_variables,
+            &context.managed_relations,
+        )
+        .await
+    }
+
+    /// Renders and sets the attributes on the object. Arrays are synced as multi-value attributes,
+    /// keeping the values of the managed relations.
+    async fn apply_attributes(
+        &self,
+        object: &mut NewObject,
+        hostname: &str,
+        attributes: &HashMap<String, ProcessableValue>,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        managed_relations: &HashMap<String, Vec<serde_json::Value>>,
+    ) -> anyhow::Result<()> {
+        for (key, value) in attributes {
+            let is_append = value.is_append;
+            for query in value.queries() {
+                self.resolve_query(query, variables).await.map_err(|err| {
+                    anyhow::anyhow!("Unable to resolve {key} of {hostname}: {err}")
+                })?;
+            }
+            let value =
+                value.render_with(variables, &|query| self.query_result(query, variables))?;
+            if let serde_json::Value::Array(values) = value {
+                let managed = managed_relations.get(key).map(Vec::as_slice);
+                self.sync_multi_attribute(
+                    object,
+                    hostname,
+                    key,
+                    values,
+                    managed.unwrap_or_default(),
+                    is_append,
+                )?;
+
+                continue;
+            }
+            object.set(key, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the query against Serveradmin, unless the same query was already resolved
+    async fn resolve_query(
+        &self,
+        query: &ValueQuery,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+    ) -> anyhow::Result<()> {
+        let filters = query.render_filters(variables)?;
+        let key = query_key(query, &filters);
+        if self.query_results.lock().unwrap().contains_key(&key) {
+            return Ok(());
+        }
 
-        let network = route_network
-            .one()
-            .or_else(|_| project_network.one().or_else(|_| public_network.one()))
-            .map_err(|err| anyhow::anyhow!("Unable to get network: {err}"))?;
-        let intern_ip = network.get("intern_ip").as_str().unwrap().to_string();
-        let network = intern_ip.parse::<IpNet>()?;
-        let taken_ips = Query::builder()
-            .filter("intern_ip", contained_only_by(intern_ip))
-            .restrict(["intern_ip"])
+        let mut builder = Query::builder();
+        for (attribute, value) in &filters {
+            builder = builder.filter(attribute, value.clone());
+        }
+        let values = builder
+            .restrict([query.restrict.as_str()])
             .build()
             .request()
             .await
-            .map_err(|err| anyhow::anyhow!("Unable to query taken IPs {err}"))?
+            .map_err(|err| anyhow::anyhow!("The query {filters:?} failed: {err}"))?
             .all()
             .into_iter()
-            .map(|object| object.get("intern_ip").as_str().unwrap().to_string())
+            .map(|object| object.get(&query.restrict))
+            .collect::<Vec<_>>();
+
+        let value = match query.expect {
+            QueryExpectation::One => {
+                let [value] = <[serde_json::Value; 1]>::try_from(values).map_err(|values| {
+                    anyhow::anyhow!(
+                        "The query {filters:?} found {} objects, but exactly one was expected",
+                        values.len()
+                    )
+                })?;
+
+                value
+            }
+            QueryExpectation::Many => serde_json::Value::Array(values),
+        };
+
+        self.query_results.lock().unwrap().insert(key, value);
+
+        Ok(())
+    }
+
+    fn query_result(
+        &self,
+        query: &ValueQuery,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let key = query_key(query, &query.render_filters(variables)?);
+
+        self.query_results
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("The query {key} was not resolved"))
+    }
+
+    /// Makes the values of a multi-value attribute equal to the given values.
+    ///
+    /// With `append` values which are not declared anymore are kept on the object.
+    fn sync_multi_attribute(
+        &self,
+        object: &mut NewObject,
+        hostname: &str,
+        key: &str,
+        values: Vec<serde_json::Value>,
+        managed: &[serde_json::Value],
+        append: bool,
+    ) -> anyhow::Result<()> {
+        let current = match object.get(key) {
+            serde_json::Value::Array(current) => current,
+            serde_json::Value::Null => Vec::new(),
+            current => vec![current],
+        };
+
+        let (remove, add) = multi_attribute_changes(&current, &values, managed, append);
+        for value in remove {
+            self.record_change(hostname, key, format!("remove {}", display_value(&value)));
+            object.remove(key, value)?;
+        }
+        for value in add {
+            object.add(key, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the object into the given state, if the transition from its current one is allowed
+    fn apply_state(
+        &self,
+        object: &mut NewObject,
+        hostname: &str,
+        state: ObjectState,
+    ) -> anyhow::Result<()> {
+        if object.is_new() {
+            object.set("state", state.to_string())?;
+
+            return Ok(());
+        }
+
+        let current = object.get("state");
+        let current = current.as_str().unwrap_or_default();
+        if current == state.to_string() {
+            return Ok(());
+        }
+
+        match current.parse::<ObjectState>() {
+            // Objects without a state may start in any
+            _ if current.is_empty() => {}
+            Ok(current) if current.can_transition(state) => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{hostname} can not be moved from state {current} to {state}"
+                ))
+            }
+        }
+
+        object.set("state", state.to_string())?;
+        let current = if current.is_empty() { "unset" } else { current };
+        self.record_change(hostname, "state", format!("{current} -> {state}"));
+
+        Ok(())
+    }
+
+    fn record_change(&self, hostname: &str, attribute: &str, change: String) {
+        self.planned_changes.lock().unwrap().push(PlannedChange {
+            hostname: hostname.to_string(),
+            attribute: attribute.to_string(),
+            change,
+        });
+    }
+
+    /// Changes, which are not obvious from the created or updated objects alone
+    pub fn planned_changes(&self) -> Vec<PlannedChange> {
+        self.planned_changes.lock().unwrap().clone()
+    }
+
+    pub fn network_usage(&self) -> Vec<NetworkUsage> {
+        let mut usage = self
+            .network_ips
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(network, ips)| NetworkUsage {
+                network: format!("{network} ({})", ips.network),
+                size: ips.size(),
+                taken: ips.initially_taken,
+                allocated: ips.allocated,
+                missing: ips.missing,
+            })
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| a.network.cmp(&b.network));
+
+        usage
+    }
+
+    /// Compares the resources of the placed VMs with the capacity of their hypervisors.
+    ///
+    /// VMs of the plan already on a hypervisor are only counted with their planned size.
+    pub async fn hypervisor_capacity(&self) -> anyhow::Result<Vec<HypervisorCapacity>> {
+        let demands = self.vm_demands.lock().unwrap().clone();
+        let mut targets = demands
+            .iter()
+            .filter_map(|demand| demand.target.clone())
             .collect::<Vec<_>>();
+        targets.sort();
+        targets.dedup();
+
+        futures::future::try_join_all(targets.into_iter().map(|target| {
+            let demands = &demands;
+
+            Box::pin(async move {
+                let name = &target.name;
+                let restrict = ["hostname"]
+                    .into_iter()
+                    .chain(SizeProfile::ATTRIBUTES)
+                    .collect::<Vec<_>>();
+                // Groups are the hypervisors, which have the group in the same attribute as the VMs
+                let hypervisors = match target.scope {
+                    CapacityScope::Hypervisor => Query::builder().filter("hostname", name.clone()),
+                    CapacityScope::HypervisorGroup => Query::builder()
+                        .filter("servertype", "hypervisor".to_string())
+                        .filter(target.attribute.as_str(), name.clone()),
+                }
+                .restrict(restrict.clone())
+                .build()
+                .request()
+                .await
+                .map_err(|err| {
+                    anyhow::anyhow!("Unable to query the {} {name}: {err}", target.scope)
+                })?
+                .all();
+                if hypervisors.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "The {} {name} does not exist",
+                        target.scope
+                    ));
+                }
+                let others = Query::builder()
+                    .filter("servertype", "vm".to_string())
+                    .filter(target.attribute.as_str(), name.clone())
+                    .restrict(restrict)
+                    .build()
+                    .request()
+                    .await
+                    .map_err(|err| {
+                        anyhow::anyhow!(
+                            "Unable to query the VMs of the {} {name}: {err}",
+                            target.scope
+                        )
+                    })?
+                    .all();
+
+                let mut capacity = HypervisorCapacity {
+                    scope: target.scope,
+                    name: name.clone(),
+                    total: Resources::default(),
+                    used: Resources::default(),
+                    planned: Resources::default(),
+                };
+                for hypervisor in hypervisors {
+                    capacity.total += Resources::read(|attribute| hypervisor.get(attribute));
+                }
+                for vm in others {
+                    let hostname = vm.get("hostname");
+                    if demands
+                        .iter()
+                        .any(|demand| hostname == demand.hostname.as_str())
+                    {
+                        continue;
+                    }
+
+                    capacity.used += Resources::read(|attribute| vm.get(attribute));
+                }
+                for demand in demands
+                    .iter()
+                    .filter(|demand| demand.target.as_ref() == Some(&target))
+                {
+                    capacity.planned += demand.size;
+                }
+
+                anyhow::Ok(capacity)
+            })
+        }))
+        .await
+    }
+
+    /// The VMs, whose capacity cannot be checked as they have neither a placement nor a
+    /// hypervisor group
+    pub fn unchecked_vms(&self) -> Vec<String> {
+        self.vm_demands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|demand| demand.target.is_none())
+            .map(|demand| demand.hostname.clone())
+            .collect()
+    }
+
+    /// Returns a free IP of the network or None if the network is exhausted.
+    ///
+    /// Exhausted networks are recorded in the [`NetworkUsage`] so the whole plan can be rejected
+    /// before anything is committed.
+    async fn get_free_ip(&self, network: &NetworkTarget) -> anyhow::Result<Option<IpAddr>> {
+        let key = self.load_network(network).await?;
+
+        Ok(self
+            .network_ips
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(|ips| ips.get_ip()))
+    }
+
+    /// Resolves the network by its name.
+    ///
+    /// If the network type is not given explicitly, all network types are considered and the
+    /// reference has to be unambiguous.
+    pub async fn resolve_network(
+        &self,
+        network: &NetworkTarget,
+    ) -> anyhow::Result<ResolvedNetwork> {
+        let project = self.project.as_ref().cloned().unwrap_or_default();
+        let types = match network.typ {
+            Some(typ) => vec![typ],
+            None => NetworkType::ALL.to_vec(),
+        };
 
-        self.network_ips
+        let mut candidates = futures::future::try_join_all(types.into_iter().map(|typ| {
+            let query = network_query(typ, &network.name, &project);
+
+            Box::pin(async move {
+                let response = query
+                    .request()
+                    .await
+                    .map_err(|err| anyhow::anyhow!("Unable to query {typ}: {err}"))?;
+
+                anyhow::Ok(
+                    response
+                        .all()
+                        .into_iter()
+                        .map(|object| ResolvedNetwork {
+                            typ,
+                            hostname: object
+                                .get("hostname")
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                            intern_ip: object
+                                .get("intern_ip")
+                                .as_str()
+                                .unwrap_or_default()
+                                .to_string(),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        match candidates.len() {
+            0 => Err(anyhow::anyhow!("Unable to find network {network}")),
+            1 => Ok(candidates.remove(0)),
+            _ => Err(anyhow::anyhow!(
+                "The network {network} is ambiguous, set its type explicitly. Candidates:\n{}",
+                candidates
+                    .iter()
+                    .map(|candidate| format!(
+                        "  - {} {} ({})",
+                        candidate.typ, candidate.hostname, candidate.intern_ip
+                    ))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )),
+        }
+    }
+
+    /// Loads the free IPs of the network and returns the key of its pool.
+    ///
+    /// Pools are keyed by the resolved network, so references with and without an explicit type
+    /// share the same IPs.
+    async fn load_network(&self, network: &NetworkTarget) -> anyhow::Result<String> {
+        if let Some(key) = self.network_pools.lock().unwrap().get(&network.to_string()) {
+            return Ok(key.clone());
+        }
+
+        let resolved = self.resolve_network(network).await?;
+        let key = resolved.hostname;
+        if !self.network_ips.lock().unwrap().contains_key(&key) {
+            let ip_network = resolved.intern_ip.parse::<IpNet>()?;
+            let taken_ips = query_taken_ips(&ip_network, &key).await?;
+
+            // Loadbalancers load their network concurrently, the first pool has to stay as IPs
+            // may have been taken from it already
+            self.network_ips
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_insert_with(|| FreeIps::new(taken_ips, ip_network));
+        }
+        self.network_pools
             .lock()
             .unwrap()
-            .insert(network_name.to_string(), FreeIps { taken_ips, network });
+            .insert(network.to_string(), key.clone());
+
+        Ok(key)
+    }
+
+    /// Splits the objects to commit into the batches of a staged rollout.
+    ///
+    /// New objects and objects other than VMs go into the first batch, so the following batches
+    /// only roll out changes of existing VMs, per zone and/or `batch_size` VMs at a time.
+    pub fn rollout_batches(
+        &self,
+        objects: Vec<NewObject>,
+        batch_size: Option<usize>,
+        by_zone: bool,
+    ) -> Vec<Vec<NewObject>> {
+        let vm_zones = self.vm_zones.lock().unwrap().clone();
+        let zones = objects
+            .iter()
+            .map(|object| {
+                object
+                    .get("hostname")
+                    .as_str()
+                    .and_then(|hostname| vm_zones.get(hostname))
+                    .filter(|_| !object.is_new())
+                    .cloned()
+            })
+            .collect::<Vec<_>>();
+        let zones = zones.iter().map(Option::as_deref).collect::<Vec<_>>();
+
+        let mut objects = objects.into_iter().map(Some).collect::<Vec<_>>();
+        batch_indices(&zones, batch_size, by_zone)
+            .into_iter()
+            .map(|batch| {
+                batch
+                    .into_iter()
+                    .filter_map(|index| objects[index].take())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Checks every IP allocated by this plan against the current state of Serveradmin and
+    /// reallocates the ones, which have been taken by someone else in the meantime.
+    pub async fn verify_ip_allocations(
+        &self,
+        objects: &mut [NewObject],
+    ) -> anyhow::Result<Vec<IpReassignment>> {
+        let mut reassignments = Vec::new();
+
+        for _ in 0..MAX_IP_VERIFICATION_ROUNDS {
+            let allocations = self.ip_allocations.lock().unwrap().clone();
+            let conflicts =
+                futures::future::try_join_all(allocations.into_iter().map(|allocation| {
+                    Box::pin(async move {
+                        let holders = Query::builder()
+                            .filter("intern_ip", allocation.ip.to_string())
+                            .restrict(["hostname"])
+                            .build()
+                            .request()
+                            .await
+                            .map_err(|err| {
+                                anyhow::anyhow!(
+                                    "Unable to query holders of {}: {err}",
+                                    allocation.ip
+                                )
+                            })?
+                            .all()
+                            .into_iter()
+                            .filter_map(|object| object.get("hostname").as_str().map(String::from))
+                            .filter(|hostname| hostname != &allocation.hostname)
+                            .collect::<Vec<_>>();
+
+                        anyhow::Ok((allocation, holders))
+                    })
+                }))
+                .await?
+                .into_iter()
+                .filter(|(_, holders)| !holders.is_empty())
+                .collect::<Vec<_>>();
+
+            if conflicts.is_empty() {
+                return Ok(reassignments);
+            }
+
+            for (allocation, holders) in conflicts {
+                self.refresh_network(&allocation.network).await?;
+                let Some(new_ip) = self.get_free_ip(&allocation.network).await? else {
+                    return Err(anyhow::anyhow!(
+                        "{} of {} has been taken by {} and network {} has no free IPs left",
+                        allocation.ip,
+                        allocation.hostname,
+                        holders.join(", "),
+                        allocation.network,
+                    ));
+                };
+
+                for object in objects.iter_mut() {
+                    if object.get("hostname").as_str() == Some(allocation.hostname.as_str()) {
+                        object.set("intern_ip", new_ip.to_string())?;
+                    }
+                }
+
+                for stored in self.ip_allocations.lock().unwrap().iter_mut() {
+                    if stored.hostname == allocation.hostname {
+                        stored.ip = new_ip;
+                    }
+                }
 
-        self.network_ips
+                reassignments.push(IpReassignment {
+                    hostname: allocation.hostname,
+                    network: allocation.network.to_string(),
+                    old_ip: allocation.ip,
+                    new_ip,
+                    holders,
+                });
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "The IP allocations are still conflicting after {MAX_IP_VERIFICATION_ROUNDS} attempts"
+        ))
+    }
+
+    fn record_ip_allocation(&self, hostname: &str, network: &NetworkTarget, ip: IpAddr) {
+        self.ip_allocations.lock().unwrap().push(IpAllocation {
+            hostname: hostname.to_string(),
+            network: network.clone(),
+            ip,
+        });
+    }
+
+    async fn refresh_network(&self, network: &NetworkTarget) -> anyhow::Result<()> {
+        let key = self.load_network(network).await?;
+        let Some(ip_network) = self
+            .network_ips
             .lock()
             .unwrap()
-            .get_mut(network_name)
-            .and_then(|value| value.get_ip())
-            .ok_or(anyhow::anyhow!(
-                "No free IP available in network {network_name}"
-            ))
+            .get(&key)
+            .map(|ips| ips.network)
+        else {
+            return Ok(());
+        };
+        let taken_ips = query_taken_ips(&ip_network, &key).await?;
+
+        if let Some(ips) = self.network_ips.lock().unwrap().get_mut(&key) {
+            for ip in taken_ips {
+                if !ips.taken_ips.contains(&ip) {
+                    ips.taken_ips.push(ip);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The objects of the plan and the relations between them, without querying their state
+    pub async fn plan_graph(&self) -> anyhow::Result<Graph> {
+        let mut graph = Graph::default();
+        let mut specs = Vec::new();
+        let mut services = self.plan.services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(function, _)| *function);
+
+        for (function, service) in services {
+            let context = ProcessorBuildContext::new(function);
+            let variables = context.get_render_variables(&self.variables);
+            graph.add_node(function, "service");
+
+            let mut vms = Vec::new();
+            let mut instances = service.instances.iter().collect::<Vec<_>>();
+            instances.sort_by_key(|(zone, _)| *zone);
+
+            for (zone, instance) in instances {
+                let zone_id = format!("{function}/{zone}");
+                graph.add_node(&zone_id, "zone");
+                graph.add_edge(function, &zone_id, "instances");
+
+                for index in 0..instance.replicas {
+                    let vm = self.vm_hostname(zone, index, function);
+                    graph.add_node(&vm, "vm");
+                    graph.add_edge(&zone_id, &vm, "");
+                    vms.push((zone.clone(), vm));
+                }
+            }
+
+            let mut sources = HashMap::<RelationSource, Vec<String>>::from([(
+                RelationSource::Vms,
+                vms.iter().map(|(_, vm)| vm.clone()).collect(),
+            )]);
+
+            let service_specs = self.get_service_group_specs(service, &context).await?;
+            for spec in &service_specs {
+                graph.add_node(&spec.hostname, "service_group");
+                sources
+                    .entry(RelationSource::ServiceGroups)
+                    .or_default()
+                    .push(spec.hostname.clone());
+
+                for (_, vm) in &vms {
+                    graph.add_edge(vm, &spec.hostname, "service_groups");
+                }
+            }
+            specs.extend(service_specs);
+
+            for export in &service.firewall.export {
+                let Some(loadbalancer) = &export.loadbalancer else {
+                    continue;
+                };
+                let sg_hostname = render_string(&export.name, &variables, "export.name")?;
+                let zones = match loadbalancer.per_zone {
+                    true => {
+                        let mut zones = service
+                            .instances
+                            .keys()
+                            .cloned()
+                            .map(Some)
+                            .collect::<Vec<_>>();
+                        zones.sort();
+
+                        zones
+                    }
+                    false => vec![None],
+                };
+
+                for zone in zones {
+                    let zone_context = ProcessorBuildContext {
+                        zone: zone.clone(),
+                        ..ProcessorBuildContext::new(function)
+                    };
+                    let variables = zone_context.get_render_variables(&self.variables);
+                    let lb_hostname =
+                        render_string(&loadbalancer.name, &variables, "loadbalancer.name")?;
+                    graph.add_node(&lb_hostname, "loadbalancer");
+                    graph.add_edge(&lb_hostname, &sg_hostname, "service_groups");
+                    sources
+                        .entry(RelationSource::Loadbalancers)
+                        .or_default()
+                        .push(lb_hostname.clone());
+
+                    for (vm_zone, vm) in &vms {
+                        if zone.as_ref().is_none_or(|zone| zone == vm_zone) {
+                            graph.add_edge(vm, &lb_hostname, "loadbalancer");
+                        }
+                    }
+
+                    for health_check in loadbalancer.health_checks() {
+                        let hc_hostname =
+                            render_string(health_check.name(), &variables, "health_check.name")?;
+                        graph.add_node(&hc_hostname, "health_check");
+                        graph.add_edge(&lb_hostname, &hc_hostname, "health_checks");
+                        sources
+                            .entry(RelationSource::HealthChecks)
+                            .or_default()
+                            .push(hc_hostname);
+                    }
+                }
+            }
+
+            for resource in &service.resources {
+                let hostname =
+                    render_string(&resource.hostname, &variables, "resources.[*].hostname")?;
+                graph.add_node(&hostname, &resource.kind);
+                graph.add_edge(function, &hostname, "resources");
+                sources
+                    .entry(RelationSource::Resources)
+                    .or_default()
+                    .push(hostname.clone());
+
+                for (attribute, targets) in &resource.relations {
+                    for target in targets {
+                        let target = render_string(target, &variables, "resources.[*].relations")?;
+                        graph.add_node(&target, "resource");
+                        graph.add_edge(&hostname, &target, attribute);
+                    }
+                }
+            }
+
+            for relation in &service.relations {
+                let mut targets = relation
+                    .to
+                    .hostnames
+                    .iter()
+                    .map(|hostname| render_string(hostname, &variables, "relations.[*].to"))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if !relation.to.query.is_empty() {
+                    let mut query = relation
+                        .to
+                        .query
+                        .iter()
+                        .map(|(attribute, value)| {
+                            Ok(format!(
+                                "{attribute}={}",
+                                render_string(value, &variables, "relations.[*].to.query")?
+                            ))
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    query.sort();
+                    targets.push(format!("query: {}", query.join(" ")));
+                }
+
+                for target in &targets {
+                    graph.add_node(target, "resource");
+
+                    for source in sources.get(&relation.from).into_iter().flatten() {
+                        graph.add_edge(source, target, &relation.attribute);
+                    }
+                }
+            }
+        }
+
+        let functions = specs
+            .iter()
+            .map(|spec| (spec.hostname.clone(), spec.function.clone()))
+            .collect::<HashMap<_, _>>();
+
+        for spec in &specs {
+            for target in &spec.allow_to {
+                if target == &spec.hostname {
+                    continue;
+                }
+
+                graph.add_node(target, "service_group");
+                graph.add_edge(&spec.hostname, target, "sg_allow_to");
+
+                if let Some(target_function) = functions.get(target) {
+                    if target_function != &spec.function {
+                        graph.add_edge(&spec.function, target_function, "import");
+                    }
+                }
+            }
+
+            for source in &spec.allow_from {
+                if source == &spec.hostname {
+                    continue;
+                }
+
+                graph.add_node(source, "service_group");
+                graph.add_edge(&spec.hostname, source, "sg_allow_from");
+            }
+
+            for cidr in spec.cidr_allow_from.iter().chain(&spec.cidr_allow_to) {
+                graph.add_node(cidr, "peer");
+                graph.add_edge(&spec.hostname, cidr, "cidr");
+            }
+        }
+
+        Ok(graph)
+    }
+
+    /// The service groups of every service in the plan as the processor would create them
+    pub async fn service_group_specs(&self) -> anyhow::Result<Vec<ServiceGroupSpec>> {
+        let mut specs = Vec::new();
+        let mut services = self.plan.services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(function, _)| *function);
+
+        for (function, service) in services {
+            let context = ProcessorBuildContext::new(function);
+
+            specs.extend(self.get_service_group_specs(service, &context).await?);
+        }
+
+        Ok(specs)
+    }
+
+    /// Checks that the imports and exports of all services line up
+    pub fn lint_firewall(&self) -> anyhow::Result<Vec<LintFinding>> {
+        let mut findings = Vec::new();
+        let mut services = self.plan.services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(function, _)| *function);
+
+        // (service, export name, ports, has loadbalancer) of every export in this plan
+        let mut exports = Vec::new();
+        for (function, service) in &services {
+            let context = ProcessorBuildContext::new(function);
+            let variables = context.get_render_variables(&self.variables);
+
+            for export in &service.firewall.export {
+                let name = render_string(&export.name, &variables, "firewall.export.[*].name")?;
+                exports.push((
+                    function.to_string(),
+                    name,
+                    &export.ports,
+                    export.loadbalancer.is_some(),
+                ));
+            }
+        }
+        let mut imported = vec![false; exports.len()];
+
+        for (function, service) in &services {
+            let context = ProcessorBuildContext::new(function);
+            let variables = context.get_render_variables(&self.variables);
+            let firewall = &service.firewall;
+
+            findings.extend(duplicate_ports(function, "intern", &firewall.intern));
+            for (_, name, ports, _) in exports.iter().filter(|(service, ..)| service == *function) {
+                findings.extend(duplicate_ports(function, &format!("export {name}"), ports));
+
+                for intern in &firewall.intern {
+                    for port in ports.iter().filter(|port| intern.overlaps(port)) {
+                        findings.push(LintFinding::warning(
+                            function,
+                            format!("intern rule {intern} shadows {port} of export {name}"),
+                        ));
+                    }
+                }
+            }
+            for external in &firewall.external {
+                let name = render_string(&external.name, &variables, "firewall.external.[*].name")?;
+                findings.extend(duplicate_ports(
+                    function,
+                    &format!("external {name}"),
+                    &external.ports,
+                ));
+            }
+
+            for import in &firewall.import {
+                let target = import
+                    .service
+                    .as_ref()
+                    .map(|service| {
+                        render_string(service, &variables, "firewall.import.[*].service")
+                    })
+                    .transpose()?;
+                let references = import
+                    .references
+                    .iter()
+                    .map(|reference| {
+                        render_string(reference, &variables, "firewall.import.[*].references")
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if target.is_none() && references.is_empty() {
+                    findings.push(LintFinding::error(
+                        function,
+                        "an import needs a service or references".to_string(),
+                    ));
+
+                    continue;
+                }
+                let label = target.clone().unwrap_or_else(|| references.join(", "));
+                findings.extend(duplicate_ports(
+                    function,
+                    &format!("import of {label}"),
+                    &import.ports,
+                ));
+
+                let target_ports = if !references.is_empty() {
+                    let mut ports = Vec::new();
+                    for (index, (_, name, export_ports, _)) in exports.iter().enumerate() {
+                        if references.contains(name) {
+                            imported[index] = true;
+                            ports.extend(export_ports.iter().cloned());
+                        }
+                    }
+
+                    // Service groups outside of this plan cannot be checked
+                    if ports.is_empty() {
+                        continue;
+                    }
+
+                    ports
+                } else if let Some((plan_name, service_name)) =
+                    target.as_ref().and_then(|target| target.split_once('#'))
+                {
+                    let plan = match self.load_referenced_plan(plan_name) {
+                        Ok(plan) => plan,
+                        Err(err) => {
+                            findings.push(LintFinding::error(
+                                function,
+                                format!("Unable to check the import of {label}: {err}"),
+                            ));
+
+                            continue;
+                        }
+                    };
+                    let Some(target_service) = plan.services.get(service_name) else {
+                        findings.push(LintFinding::error(
+                            function,
+                            format!("The imported service {label} does not exist"),
+                        ));
+
+                        continue;
+                    };
+
+                    target_service
+                        .firewall
+                        .export
+                        .iter()
+                        .flat_map(|export| export.ports.iter().cloned())
+                        .collect()
+                } else {
+                    if !self.plan.services.contains_key(&label) {
+                        findings.push(LintFinding::error(
+                            function,
+                            format!("The imported service {label:?} does not exist"),
+                        ));
+
+                        continue;
+                    }
+
+                    let mut ports = Vec::new();
+                    for (index, (service, _, export_ports, _)) in exports.iter().enumerate() {
+                        if service == &label {
+                            imported[index] = true;
+                            ports.extend(export_ports.iter().cloned());
+                        }
+                    }
+
+                    ports
+                };
+
+                let uncovered = uncovered_ports(&import.ports, &target_ports);
+                if uncovered.is_empty() {
+                    continue;
+                }
+
+                let exported = target_ports
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                findings.push(LintFinding::error(
+                    function,
+                    format!(
+                        "imports {} from {label}, which only exports {}",
+                        uncovered
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        if exported.is_empty() {
+                            "nothing".to_string()
+                        } else {
+                            exported.join(", ")
+                        }
+                    ),
+                ));
+            }
+        }
+
+        for ((service, name, _, has_loadbalancer), imported) in exports.iter().zip(imported) {
+            if !imported && !has_loadbalancer {
+                findings.push(LintFinding::warning(
+                    service,
+                    format!("export {name} is not imported by any service of this plan"),
+                ));
+            }
+        }
+
+        findings.sort_by(|a, b| (a.severity, &a.service).cmp(&(b.severity, &b.service)));
+
+        Ok(findings)
     }
 
     async fn get_new_service_groups(
@@ -356,150 +2333,405 @@ impl ServicePlanProcessor {
         service: &Service,
         context: &mut ProcessorBuildContext,
     ) -> anyhow::Result<Vec<NewObject>> {
-        let mut rules = Vec::new();
+        let specs = self.get_service_group_specs(service, context).await?;
+
+        futures::future::try_join_all(
+            specs
+                .into_iter()
+                .map(|spec| Box::pin(self.create_service_group(spec))),
+        )
+        .await
+    }
+
+    async fn get_service_group_specs(
+        &self,
+        service: &Service,
+        context: &ProcessorBuildContext,
+    ) -> anyhow::Result<Vec<ServiceGroupSpec>> {
+        let mut specs = Vec::new();
         let context_variables = context.get_render_variables(&self.variables);
 
         let exports_mapped =
-            futures::future::try_join_all(service.firewall.export.iter().map(|export| {
-                self.create_export_sg(export, &context_variables, &context.function)
+            futures::future::try_join_all(
+                service.firewall.export.iter().map(|export| {
+                    self.export_sg_spec(export, &context_variables, &context.function)
+                }),
+            );
+
+        let imports_mapped =
+            futures::future::try_join_all(
+                service.firewall.import.iter().map(|import| {
+                    self.import_sg_spec(import, &context_variables, &context.function)
+                }),
+            );
+
+        let externals_mapped =
+            futures::future::try_join_all(service.firewall.external.iter().map(|external| {
+                self.external_sg_spec(external, &context_variables, &context.function)
             }));
 
-        let imports_mapped =
-            futures::future::try_join_all(service.firewall.import.iter().map(|import| {
-                self.create_import_sg(import, &context_variables, &context.function)
-            }));
+        let (export, import, external) =
+            futures::try_join!(exports_mapped, imports_mapped, externals_mapped)?;
+
+        specs.extend(export);
+        specs.extend(import);
+        specs.extend(external);
+        specs.extend(self.intern_sg_spec(service, &context.function));
+
+        Ok(specs)
+    }
+
+    async fn create_service_group(&self, spec: ServiceGroupSpec) -> anyhow::Result<NewObject> {
+        let context = ProcessorBuildContext::new(&spec.function);
+        let mut service_group = self
+            .create_base_object(
+                "service_group",
+                &spec.hostname,
+                &spec.function,
+                &HashMap::new(),
+                &context.get_render_variables(&self.variables),
+                &HashMap::new(),
+            )
+            .await?;
+
+        for (attribute, ports) in [
+            ("protocol_ports_inbound", &spec.inbound),
+            ("protocol_ports_outbound", &spec.outbound),
+        ] {
+            let ports = ports
+                .iter()
+                .flat_map(PortSpec::attribute_values)
+                .map(serde_json::Value::String)
+                .collect();
+            self.sync_multi_attribute(
+                &mut service_group,
+                &spec.hostname,
+                attribute,
+                ports,
+                &[],
+                false,
+            )?;
+        }
 
-        let (export, import, intern) = futures::try_join!(
-            exports_mapped,
-            imports_mapped,
-            self.create_intern_sg(service, &context.function)
-        )?;
+        if spec.kind == ServiceGroupKind::External {
+            let kind = self.kinds.get("service_group");
+            for (setting, cidrs) in [
+                ("cidr_allow_from", &spec.cidr_allow_from),
+                ("cidr_allow_to", &spec.cidr_allow_to),
+            ] {
+                // Plans without peer CIDRs work without configuring their attributes
+                if cidrs.is_empty() && !kind.attributes.contains_key(setting) {
+                    continue;
+                }
 
-        rules.extend(export);
-        rules.extend(import);
-        if let Some(intern) = intern {
-            rules.push(intern);
+                let cidrs = cidrs
+                    .iter()
+                    .cloned()
+                    .map(serde_json::Value::String)
+                    .collect();
+                self.sync_multi_attribute(
+                    &mut service_group,
+                    &spec.hostname,
+                    kind.attribute(setting)?,
+                    cidrs,
+                    &[],
+                    false,
+                )?;
+            }
         }
 
-        Ok(rules)
+        service_group.deferred(|server| {
+            for hostname in spec.allow_from {
+                server.add("sg_allow_from", hostname)?;
+            }
+            for hostname in spec.allow_to {
+                server.add("sg_allow_to", hostname)?;
+            }
+
+            anyhow::Ok(())
+        })?;
+
+        anyhow::Ok(service_group)
     }
 
-    async fn create_export_sg(
+    async fn export_sg_spec(
         &self,
         export: &FirewallExport,
         context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
         function: &str,
-    ) -> anyhow::Result<NewObject> {
-        let hostname = export.name.render(context_variables)?;
-        let plain_hostname = hostname.as_str().ok_or(anyhow::anyhow!(
-            "services.{}.firewall.export.[*].name has to be a string",
-            &function
-        ))?;
-        let mut service_group = self.create_sg_base_object(plain_hostname, function).await?;
+    ) -> anyhow::Result<ServiceGroupSpec> {
+        let serde_json::Value::String(hostname) = export.name.render(context_variables)? else {
+            return Err(anyhow::anyhow!(
+                "services.{function}.firewall.export.[*].name has to be a string"
+            ));
+        };
+        let mut spec = ServiceGroupSpec::new(ServiceGroupKind::Export, hostname, function);
+        spec.inbound = export.ports.clone();
 
-        service_group.set("hostname", hostname)?;
+        anyhow::Ok(spec)
+    }
 
-        for port in &export.ports {
-            service_group.add("protocol_ports_inbound", port.clone())?;
-        }
+    async fn import_sg_spec(
+        &self,
+        import: &ExternalFirewallRule,
+        context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        function: &str,
+    ) -> anyhow::Result<ServiceGroupSpec> {
+        let (service, references) = self
+            .resolve_import_references(import, context_variables)
+            .await?;
+        let hostname = match (&import.client_group, &service) {
+            (Some(client_group), service) => {
+                let mut variables = context_variables.clone();
+                if let Some(service) = &service {
+                    variables.insert("service".to_string(), &service.name);
+                }
 
-        anyhow::Ok(service_group)
+                render_string(client_group, &variables, "firewall.import.[*].client_group")?
+            }
+            (None, Some(service)) => format!(
+                "{}-{}-{}-clients.{}.sg",
+                self.subproject.as_ref().cloned().unwrap_or_default(),
+                self.environment.as_ref().cloned().unwrap_or_default(),
+                service.qualified_name(),
+                self.project.as_ref().cloned().unwrap_or_default(),
+            ),
+            (None, None) => {
+                return Err(anyhow::anyhow!(
+                    "firewall.import.[*].client_group is required when only references are given"
+                ))
+            }
+        };
+        let mut spec = ServiceGroupSpec::new(ServiceGroupKind::Import, hostname, function);
+        spec.outbound = import.ports.clone();
+        spec.allow_to = references;
+
+        anyhow::Ok(spec)
     }
 
-    async fn create_import_sg(
+    /// Returns the imported service and the service groups it exports.
+    ///
+    /// Services of other plans or projects and explicitly referenced service groups, which are not
+    /// exported by this plan, have to exist in Serveradmin already.
+    async fn resolve_import_references(
         &self,
         import: &ExternalFirewallRule,
         context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
-        function: &str,
-    ) -> anyhow::Result<NewObject> {
-        let hostname = format!(
-            "{}-{}-{}-clients.{}.sg",
-            self.subproject.as_ref().cloned().unwrap_or_default(),
-            self.environment.as_ref().cloned().unwrap_or_default(),
-            &import
-                .service
-                .render(context_variables)?
-                .as_str()
-                .unwrap_or_default(),
-            self.project.as_ref().cloned().unwrap_or_default(),
-        );
-        let mut service_group = self.create_sg_base_object(&hostname, function).await?;
-        service_group.set("hostname", hostname)?;
+    ) -> anyhow::Result<(Option<ImportedService>, Vec<String>)> {
+        let service = import
+            .service
+            .as_ref()
+            .map(|service| render_string(service, context_variables, "firewall.import.[*].service"))
+            .transpose()?;
+        let project = import
+            .project
+            .as_ref()
+            .map(|project| render_string(project, context_variables, "firewall.import.[*].project"))
+            .transpose()?;
+
+        if !import.references.is_empty() {
+            let references = import
+                .references
+                .iter()
+                .map(|reference| {
+                    render_string(
+                        reference,
+                        context_variables,
+                        "firewall.import.[*].references",
+                    )
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let exported = self.exported_service_groups()?;
+
+            for reference in references
+                .iter()
+                .filter(|reference| !exported.contains(*reference))
+            {
+                if !self.object_exists(Some("service_group"), reference).await? {
+                    return Err(anyhow::anyhow!(
+                        "The imported service group {reference} does not exist in Serveradmin"
+                    ));
+                }
+            }
+
+            let service = service.map(|service| ImportedService::parse(&service, project));
 
-        for port in &import.ports {
-            service_group.add("protocol_ports_outbound", port.clone())?;
+            return Ok((service, references));
         }
 
-        service_group.deferred(|server| {
-            import.references.iter().try_for_each(|reference| {
-                server.add("sg_allow_to", reference.render(context_variables)?)?;
+        let Some(service) = service else {
+            return Err(anyhow::anyhow!(
+                "firewall.import.[*].service is required unless references are given"
+            ));
+        };
+        let imported = ImportedService::parse(&service, project.clone());
+        let plan_name = imported.plan.as_deref();
+        let service_name = imported.name.clone();
 
-                anyhow::Ok(())
-            })?;
+        let referenced_plan;
+        let plan = match plan_name {
+            Some(plan_name) => {
+                referenced_plan = self.load_referenced_plan(plan_name)?;
 
-            anyhow::Ok(())
-        })?;
+                &referenced_plan
+            }
+            None => &self.plan,
+        };
+        let Some(target) = plan.services.get(&service_name) else {
+            return Err(anyhow::anyhow!(
+                "The imported service {service:?} does not exist"
+            ));
+        };
 
-        anyhow::Ok(service_group)
+        let mut variables = context_variables.clone();
+        variables.insert("function".to_string(), &service_name);
+        if let Some(project) = &project {
+            variables.insert("project".to_string(), project);
+        }
+
+        let references = target
+            .firewall
+            .export
+            .iter()
+            .map(|export| match export.name.render(&variables)? {
+                serde_json::Value::String(reference) => Ok(reference),
+                _ => Err(anyhow::anyhow!(
+                    "The export names of {service:?} have to be strings"
+                )),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if references.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The imported service {service:?} does not export any service groups"
+            ));
+        }
+
+        if plan_name.is_some() || project.is_some() {
+            for reference in &references {
+                if !self.object_exists(Some("service_group"), reference).await? {
+                    return Err(anyhow::anyhow!(
+                        "The service group {reference} exported by {service:?} does not exist in Serveradmin"
+                    ));
+                }
+            }
+        }
+
+        Ok((Some(imported), references))
     }
 
-    async fn create_intern_sg(
-        &self,
-        service: &Service,
-        function: &str,
-    ) -> anyhow::Result<Option<NewObject>> {
-        if service.firewall.intern.is_empty() {
-            return anyhow::Ok(None);
+    fn load_referenced_plan(&self, name: &str) -> anyhow::Result<ServicePlan> {
+        if let Some(plan) = self.referenced_plans.lock().unwrap().get(name) {
+            return Ok(plan.clone());
         }
 
-        let hostname = format!(
-            "{}-{}-{}-intern.{}.sg",
-            self.subproject.as_ref().cloned().unwrap_or_default(),
-            self.environment.as_ref().cloned().unwrap_or_default(),
-            function,
-            self.project.as_ref().cloned().unwrap_or_default(),
-        );
+        let plan_path = self.plan_path.clone().unwrap_or_default();
+        let plan = ServicePlan::load(&ServicePlan::referenced_path(&plan_path, name))?;
+        self.referenced_plans
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), plan.clone());
 
-        let mut service_group = self.create_sg_base_object(&hostname, function).await?;
+        Ok(plan)
+    }
 
-        service_group
-            .set("hostname", hostname.clone())?
-            .add("sg_allow_from", hostname.clone())?
-            .add("sg_allow_to", hostname.clone())?;
+    /// The names of the service groups exported by the services of this plan
+    fn exported_service_groups(&self) -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
 
-        for port in &service.firewall.intern {
-            service_group.deferred(|server| {
-                server
-                    .add("protocol_ports_inbound", port.clone())?
-                    .add("protocol_ports_outbound", port.clone())?;
-                anyhow::Ok(())
-            })?;
+        for (function, service) in &self.plan.services {
+            let context = ProcessorBuildContext::new(function);
+            let variables = context.get_render_variables(&self.variables);
+
+            for export in &service.firewall.export {
+                names.push(render_string(
+                    &export.name,
+                    &variables,
+                    "firewall.export.[*].name",
+                )?);
+            }
         }
 
-        anyhow::Ok(Some(service_group))
+        Ok(names)
     }
 
-    async fn create_sg_base_object(
+    async fn object_exists(
         &self,
+        servertype: Option<&str>,
         hostname: &str,
+    ) -> anyhow::Result<bool> {
+        let mut query = Query::builder().filter("hostname", hostname.to_string());
+        if let Some(servertype) = servertype {
+            query = query.filter("servertype", servertype.to_string());
+        }
+
+        let objects = query
+            .restrict(["hostname"])
+            .build()
+            .request()
+            .await
+            .map_err(|err| anyhow::anyhow!("Unable to query {hostname}: {err}"))?;
+
+        Ok(!objects.all().is_empty())
+    }
+
+    async fn external_sg_spec(
+        &self,
+        external: &ExternalPeerRule,
+        context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
         function: &str,
-    ) -> anyhow::Result<NewObject> {
-        let mut new_object = NewObject::get_or_create("service_group", hostname).await?;
-        new_object.set("hostname", hostname.to_string())?;
+    ) -> anyhow::Result<ServiceGroupSpec> {
+        let serde_json::Value::String(hostname) = external.name.render(context_variables)? else {
+            return Err(anyhow::anyhow!(
+                "services.{function}.firewall.external.[*].name has to be a string"
+            ));
+        };
 
-        if let Some(value) = &self.project {
-            new_object.set("project", value.clone())?;
+        let mut peers = external
+            .cidrs
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        for network in &external.networks {
+            let network = network.render(context_variables)?;
+            peers.push(self.resolve_network(&network).await?.intern_ip);
         }
-        if let Some(value) = &self.subproject {
-            new_object.set("subproject", value.clone())?;
+
+        let mut spec = ServiceGroupSpec::new(ServiceGroupKind::External, hostname, function);
+        match external.direction {
+            FirewallDirection::Inbound => {
+                spec.inbound = external.ports.clone();
+                spec.cidr_allow_from = peers;
+            }
+            FirewallDirection::Outbound => {
+                spec.outbound = external.ports.clone();
+                spec.cidr_allow_to = peers;
+            }
         }
-        if let Some(value) = &self.environment {
-            new_object.set("environment", value.clone())?;
+
+        anyhow::Ok(spec)
+    }
+
+    fn intern_sg_spec(&self, service: &Service, function: &str) -> Option<ServiceGroupSpec> {
+        if service.firewall.intern.is_empty() {
+            return None;
         }
 
-        new_object.set("function", function.to_string())?;
+        let hostname = format!(
+            "{}-{}-{}-intern.{}.sg",
+            self.subproject.as_ref().cloned().unwrap_or_default(),
+            self.environment.as_ref().cloned().unwrap_or_default(),
+            function,
+            self.project.as_ref().cloned().unwrap_or_default(),
+        );
+
+        let mut spec = ServiceGroupSpec::new(ServiceGroupKind::Intern, hostname.clone(), function);
+        spec.inbound = service.firewall.intern.clone();
+        spec.outbound = service.firewall.intern.clone();
+        spec.allow_from = vec![hostname.clone()];
+        spec.allow_to = vec![hostname];
 
-        Ok(new_object)
+        Some(spec)
     }
 
     async fn create_loadbalancer(
@@ -521,126 +2753,580 @@ impl ServicePlanProcessor {
                 "The loadbalancer hostname has to be a string"
             ));
         };
-        let hc_name = match &loadbalancer_config.health_check {
-            crate::config::HealthCheck::Import { name } => {
+        let mut hc_names = Vec::new();
+        for health_check in loadbalancer_config.health_checks() {
+            let (hc_name, hc) = self
+                .create_health_check(health_check, context_variables, function)
+                .await?;
+
+            hc_names.push(hc_name);
+            objects.extend(hc);
+        }
+
+        if hc_names.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The loadbalancer {lb_hostname} needs at least one health check"
+            ));
+        }
+
+        let network = loadbalancer_config
+            .public_network
+            .render(context_variables)?;
+        self.load_network(&network).await?;
+
+        let mut loadbalancer = self
+            .create_base_object(
+                "loadbalancer",
+                &lb_hostname,
+                function,
+                &HashMap::new(),
+                context_variables,
+                &HashMap::new(),
+            )
+            .await?;
+        loadbalancer
+            .set("min_nodes", loadbalancer_config.min_nodes.unwrap_or(1))?
+            .set(
+                "min_nodes_action",
+                serde_json::to_value(loadbalancer_config.min_nodes_action.unwrap_or_default())?,
+            )?
+            .set(
+                "symmetric_nat",
+                serde_json::Value::Bool(loadbalancer_config.symmetric_nat.unwrap_or(false)),
+            )?;
+
+        let kind = self.kinds.get("loadbalancer");
+        if let Some(algorithm) = &loadbalancer_config.algorithm {
+            loadbalancer.set(
+                kind.attribute("algorithm")?,
+                serde_json::to_value(algorithm)?,
+            )?;
+        }
+        if let Some(persistence) = &loadbalancer_config.persistence {
+            loadbalancer.set(
+                kind.attribute("persistence")?,
+                serde_json::to_value(persistence)?,
+            )?;
+        }
+        if !loadbalancer_config.listeners.is_empty() {
+            let listeners = loadbalancer_config
+                .listeners
+                .iter()
+                .map(|listener| serde_json::Value::String(listener.to_string()))
+                .collect();
+            self.sync_multi_attribute(
+                &mut loadbalancer,
+                &lb_hostname,
+                kind.attribute("listeners")?,
+                listeners,
+                &[],
+                false,
+            )?;
+        }
+        self.apply_attributes(
+            &mut loadbalancer,
+            &lb_hostname,
+            &loadbalancer_config.extra,
+            context_variables,
+            &HashMap::new(),
+        )
+        .await?;
+
+        if loadbalancer.get("intern_ip").is_null() {
+            if let Some(lb_ip) = self.get_free_ip(&network).await? {
+                loadbalancer.set("intern_ip", lb_ip.to_string())?;
+                self.record_ip_allocation(&lb_hostname, &network, lb_ip);
+            }
+        }
+
+        loadbalancer.deferred(|server| {
+            server.add("service_groups", sg_hostname)?;
+            for hc_name in hc_names {
+                server.add("health_checks", hc_name)?;
+            }
+
+            anyhow::Ok(())
+        })?;
+
+        objects.push(loadbalancer);
+
+        Ok(objects)
+    }
+
+    /// Returns the health check's hostname and the health check object, if it is created by the plan
+    async fn create_health_check(
+        &self,
+        health_check: &HealthCheck,
+        context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        function: &str,
+    ) -> anyhow::Result<(String, Option<NewObject>)> {
+        match health_check {
+            HealthCheck::Import { name } => {
                 let serde_json::Value::String(name) = name.render(context_variables)? else {
                     return Err(anyhow::anyhow!(
                         "The healthcheck hostname has to be a string"
                     ));
                 };
 
-                name
+                Ok((name, None))
             }
-            crate::config::HealthCheck::Create(hc_config) => {
+            HealthCheck::Create(hc_config) => {
                 let serde_json::Value::String(name) = hc_config.name.render(context_variables)?
                 else {
                     return Err(anyhow::anyhow!(
                         "The healthcheck hostname has to be a string"
                     ));
                 };
-                let mut hc = self.create_hc_base_object(&name, hc_config.port).await?;
+                let mut hc = self
+                    .create_base_object(
+                        "health_check",
+                        &name,
+                        function,
+                        &HashMap::new(),
+                        context_variables,
+                        &HashMap::new(),
+                    )
+                    .await?;
+                hc.set("hc_port", hc_config.port as i32)?;
+
+                hc.set("hc_type", hc_config.check.name())?;
+
+                match &hc_config.check {
+                    HealthCheckType::Http(check) | HealthCheckType::Https(check) => {
+                        hc.set("hc_query", check.query.clone())?;
+
+                        if let Some(hostname) = &check.hostname {
+                            hc.set("hc_host", hostname.clone())?;
+                        }
+                        if let Some(expect) = &check.expect {
+                            hc.set("hc_expect", expect.clone())?;
+                        }
+
+                        let ok_codes = check.ok_codes.iter().map(|code| (*code).into()).collect();
+                        self.sync_multi_attribute(
+                            &mut hc,
+                            &name,
+                            "hc_ok_codes",
+                            ok_codes,
+                            &[],
+                            false,
+                        )?;
+                        let drain_codes = check
+                            .drain_codes
+                            .iter()
+                            .map(|code| (*code).into())
+                            .collect();
+                        self.sync_multi_attribute(
+                            &mut hc,
+                            &name,
+                            "hc_drain_codes",
+                            drain_codes,
+                            &[],
+                            false,
+                        )?;
+                    }
+                    HealthCheckType::Tcp(_) => {}
+                    HealthCheckType::Postgres(check) | HealthCheckType::Mysql(check) => {
+                        hc.set("hc_user", check.user.clone())?
+                            .set("hc_dbname", check.db_name.clone())?;
+
+                        if let Some(query) = &check.query {
+                            hc.set("hc_query", query.clone())?;
+                        }
+                    }
+                    HealthCheckType::Dns(check) => {
+                        hc.set("hc_host", check.hostname.clone())?;
 
-                if !hc_config.typ.is_empty() {
-                    hc.set("hc_type", hc_config.typ.clone())?;
+                        if let Some(expect) = &check.expect {
+                            hc.set("hc_expect", expect.clone())?;
+                        }
+                    }
                 }
 
-                if !hc_config.query.is_empty() {
-                    hc.set("hc_query", hc_config.query.clone())?;
+                let timing = &hc_config.timing;
+                if let Some(interval) = timing.interval {
+                    hc.set("hc_interval", interval)?;
                 }
-
-                if !hc_config.user.is_empty() {
-                    hc.set("hc_user", hc_config.user.clone())?;
+                if let Some(timeout) = timing.timeout {
+                    hc.set("hc_timeout", timeout)?;
                 }
-
-                if !hc_config.hostname.is_empty() {
-                    hc.set("hc_host", hc_config.hostname.clone())?;
+                if let Some(rise) = timing.rise {
+                    hc.set("hc_rise", rise)?;
                 }
-
-                if !hc_config.db_name.is_empty() {
-                    hc.set("hc_dbname", hc_config.db_name.clone())?;
+                if let Some(fall) = timing.fall {
+                    hc.set("hc_fall", fall)?;
                 }
 
-                hc_config
-                    .drain_codes
-                    .iter()
-                    .try_for_each(|code| hc.add("hc_drain_codes", *code).map(|_| ()))?;
+                Ok((name, Some(hc)))
+            }
+        }
+    }
 
-                hc_config
-                    .ok_codes
+    async fn create_resource(
+        &self,
+        resource: &ServiceResource,
+        context_variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        function: &str,
+    ) -> anyhow::Result<(NewObject, Vec<PlannedRelation>)> {
+        let hostname = render_string(
+            &resource.hostname,
+            context_variables,
+            "resources.[*].hostname",
+        )?;
+        let object = self
+            .create_base_object(
+                &resource.kind,
+                &hostname,
+                function,
+                &resource.attributes,
+                context_variables,
+                &HashMap::new(),
+            )
+            .await?;
+
+        let mut relations = Vec::new();
+        let mut attributes = resource.relations.iter().collect::<Vec<_>>();
+        attributes.sort_by_key(|(attribute, _)| *attribute);
+        for (attribute, targets) in attributes {
+            let location =
+                format!("services.{function}.resources.[{hostname}].relations.{attribute}");
+            if resource.attributes.contains_key(attribute) {
+                return Err(anyhow::anyhow!(
+                    "{location} is also declared as attribute of the resource"
+                ));
+            }
+
+            relations.push(PlannedRelation {
+                sources: vec![hostname.clone()],
+                attribute: attribute.clone(),
+                targets: targets
                     .iter()
-                    .try_for_each(|code| hc.add("hc_ok_codes", *code).map(|_| ()))?;
+                    .map(|target| render_string(target, context_variables, &location))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+                location,
+            });
+        }
+
+        Ok((object, relations))
+    }
+
+    /// Gets or creates an object of the given kind with its selector and default attributes
+    async fn create_base_object(
+        &self,
+        kind: &str,
+        hostname: &str,
+        function: &str,
+        attributes: &HashMap<String, ProcessableValue>,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        managed_relations: &HashMap<String, Vec<serde_json::Value>>,
+    ) -> anyhow::Result<NewObject> {
+        let kind = self.kinds.get(kind);
+        let mut new_object = NewObject::get_or_create(&kind.servertype, hostname).await?;
+        self.claim_ownership(&mut new_object, hostname)?;
+        new_object.set("hostname", hostname.to_string())?;
 
-                objects.push(hc);
+        let mut attributes_with_defaults = kind.defaults;
+        attributes_with_defaults.extend(
+            attributes
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        self.apply_attributes(
+            &mut new_object,
+            hostname,
+            &attributes_with_defaults,
+            variables,
+            managed_relations,
+        )
+        .await?;
+
+        for selector in &kind.selectors {
+            let value = match selector {
+                SelectorAttribute::Project => self.project.clone(),
+                SelectorAttribute::Subproject => self.subproject.clone(),
+                SelectorAttribute::Environment => self.environment.clone(),
+                SelectorAttribute::Function => Some(function.to_string()),
+            };
 
-                name
+            if let Some(value) = value {
+                new_object.set(selector.attribute(), value)?;
             }
+        }
+
+        Ok(new_object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_ips(network: &str, taken: &[&str]) -> FreeIps {
+        FreeIps::new(
+            taken.iter().map(ToString::to_string).collect(),
+            network.parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn free_ips_size_excludes_network_and_broadcast() {
+        assert_eq!(free_ips("10.0.0.0/24", &[]).size(), 254);
+        assert_eq!(free_ips("10.0.0.0/31", &[]).size(), 2);
+        assert_eq!(free_ips("10.0.0.1/32", &[]).size(), 1);
+        assert_eq!(free_ips("2001:db8::1/128", &[]).size(), 1);
+        assert_eq!(free_ips("2001:db8::/120", &[]).size(), 255);
+        assert_eq!(free_ips("2001:db8::/0", &[]).size(), u128::MAX);
+    }
+
+    #[test]
+    fn free_ips_does_not_count_the_network_itself_as_taken() {
+        let ips = free_ips("10.0.0.0/24", &["10.0.0.0/24", "10.0.0.0/26", "10.0.0.5"]);
+
+        assert_eq!(ips.initially_taken, 1);
+    }
+
+    #[test]
+    fn free_ips_skips_taken_and_counts_missing() {
+        let mut ips = free_ips("10.0.0.0/30", &["10.0.0.1"]);
+
+        assert_eq!(ips.get_ip(), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(ips.get_ip(), None);
+        assert_eq!((ips.initially_taken, ips.allocated, ips.missing), (1, 1, 1));
+    }
+
+    #[test]
+    fn network_usage_counts_missing_addresses() {
+        let usage = NetworkUsage {
+            network: "10.0.0.0/30".to_string(),
+            size: 2,
+            taken: 1,
+            allocated: 1,
+            missing: 1,
         };
-        let serde_json::Value::String(network_name) = loadbalancer_config
-            .public_network
-            .render(context_variables)?
-        else {
-            return Err(anyhow::anyhow!("public_network has to be a string"));
+
+        assert_eq!(usage.remaining(), 0);
+        assert_eq!(usage.utilization(), 150.0);
+    }
+
+    #[test]
+    fn network_usage_of_empty_network_is_full() {
+        let usage = NetworkUsage {
+            network: "10.0.0.0/32".to_string(),
+            size: 0,
+            taken: 0,
+            allocated: 0,
+            missing: 0,
         };
-        let lb_ip = self.get_free_ip(&network_name).await?;
 
-        let mut loadbalancer = self.create_lb_base_object(&lb_hostname, function).await?;
-        loadbalancer
-            .set("min_nodes", 1)?
-            .set("min_nodes_action", "force_down")?
-            .set("symmetric_nat", serde_json::Value::Bool(false))?;
+        assert_eq!(usage.remaining(), 0);
+        assert_eq!(usage.utilization(), 100.0);
+    }
 
-        if loadbalancer.get("intern_ip").is_null() {
-            loadbalancer.set("intern_ip", lb_ip.to_string())?;
+    #[test]
+    fn imported_services_of_other_plans_get_their_own_client_groups() {
+        assert_eq!(
+            ImportedService::parse("psql", None).qualified_name(),
+            "psql"
+        );
+        assert_eq!(
+            ImportedService::parse("other-plan#psql", None).qualified_name(),
+            "other-plan-psql"
+        );
+        assert_eq!(
+            ImportedService::parse("other-plan#psql", Some("db".to_string())).qualified_name(),
+            "db-other-plan-psql"
+        );
+    }
+
+    #[test]
+    fn relations_cannot_take_over_managed_attributes() {
+        let service: Service = serde_yml::from_str(
+            "instances:\n  af:\n    replicas: 1\n    project_network: aw-web\n    vm:\n      os: bookworm\nvm:\n  backup_server: backup1\nresources:\n  - kind: dns_record\n    hostname: web.example.com\n    attributes:\n      target: web1",
+        )
+        .unwrap();
+
+        assert!(relation_conflict(&service, RelationSource::Vms, "service_groups").is_some());
+        assert!(relation_conflict(&service, RelationSource::Vms, "backup_server").is_some());
+        assert!(relation_conflict(&service, RelationSource::Vms, "os").is_some());
+        assert!(
+            relation_conflict(&service, RelationSource::Loadbalancers, "health_checks").is_some()
+        );
+        assert!(
+            relation_conflict(&service, RelationSource::ServiceGroups, "sg_allow_to").is_some()
+        );
+        assert!(relation_conflict(&service, RelationSource::Resources, "target").is_some());
+        assert!(relation_conflict(&service, RelationSource::Vms, "monitoring_server").is_none());
+        assert!(
+            relation_conflict(&service, RelationSource::HealthChecks, "service_groups").is_none()
+        );
+    }
+
+    fn capacity(used: u64, planned: u64, total: u64) -> HypervisorCapacity {
+        let resources = |amount| Resources {
+            num_cpu: amount,
+            memory: amount * 1024,
+            disk_size_gib: amount * 10,
+        };
+
+        HypervisorCapacity {
+            scope: CapacityScope::Hypervisor,
+            name: "hv1".to_string(),
+            total: resources(total),
+            used: resources(used),
+            planned: resources(planned),
         }
+    }
 
-        loadbalancer.deferred(|server| {
-            server.add("service_groups", sg_hostname)?;
-            server.add("health_checks", hc_name)?;
+    #[test]
+    fn resources_follow_the_size_attributes() {
+        let names = Resources::default()
+            .values()
+            .map(|(attribute, _)| attribute);
 
-            anyhow::Ok(())
-        })?;
+        assert_eq!(names, SizeProfile::ATTRIBUTES);
+    }
 
-        objects.push(loadbalancer);
+    #[test]
+    fn capacity_is_exceeded_by_used_and_planned_together() {
+        assert!(capacity(4, 4, 8).exceeded().is_empty());
+        assert_eq!(
+            capacity(4, 5, 8).exceeded(),
+            vec!["num_cpu", "memory", "disk_size_gib"]
+        );
 
-        Ok(objects)
+        let mut only_memory = capacity(0, 4, 8);
+        only_memory.planned.memory = 9 * 1024;
+        assert_eq!(only_memory.exceeded(), vec!["memory"]);
     }
 
-    async fn create_lb_base_object(
-        &self,
-        hostname: &str,
-        function: &str,
-    ) -> anyhow::Result<NewObject> {
-        let mut new_object = NewObject::get_or_create("loadbalancer", hostname).await?;
-        new_object.set("hostname", hostname.to_string())?;
+    #[test]
+    fn resources_add_up() {
+        let mut total = Resources::read(|attribute| match attribute {
+            "num_cpu" => 2.into(),
+            "memory" => 4096.into(),
+            _ => serde_json::Value::Null,
+        });
+        total += Resources {
+            num_cpu: 1,
+            memory: 1024,
+            disk_size_gib: 20,
+        };
 
-        if let Some(value) = &self.project {
-            new_object.set("project", value.clone())?;
-        }
-        if let Some(value) = &self.subproject {
-            new_object.set("subproject", value.clone())?;
-        }
-        if let Some(value) = &self.environment {
-            new_object.set("environment", value.clone())?;
-        }
+        assert_eq!(
+            total,
+            Resources {
+                num_cpu: 3,
+                memory: 5120,
+                disk_size_gib: 20,
+            }
+        );
+    }
 
-        new_object.set("function", function.to_string())?;
+    #[test]
+    fn batches_without_limits_are_one() {
+        assert_eq!(
+            batch_indices(&[None, Some("af"), Some("aw")], None, false),
+            vec![vec![0, 1, 2]]
+        );
+    }
 
-        Ok(new_object)
+    #[test]
+    fn batches_start_with_new_and_other_objects() {
+        assert_eq!(
+            batch_indices(&[Some("af"), None, Some("af"), None], Some(1), false),
+            vec![vec![1, 3], vec![0], vec![2]]
+        );
     }
 
-    async fn create_hc_base_object(&self, hostname: &str, port: u16) -> anyhow::Result<NewObject> {
-        let mut new_object = NewObject::get_or_create("health_check", hostname).await?;
-        new_object.set("hostname", hostname.to_string())?;
+    #[test]
+    fn batches_split_by_zone_and_size() {
+        let zones = [Some("aw"), Some("af"), Some("aw"), Some("aw"), Some("af")];
 
-        if let Some(value) = &self.project {
-            new_object.set("project", value.clone())?;
-        }
-        if let Some(value) = &self.subproject {
-            new_object.set("subproject", value.clone())?;
-        }
+        assert_eq!(
+            batch_indices(&zones, None, true),
+            vec![vec![1, 4], vec![0, 2, 3]]
+        );
+        assert_eq!(
+            batch_indices(&zones, Some(2), true),
+            vec![vec![1, 4], vec![0, 2], vec![3]]
+        );
+    }
 
-        new_object.set("hc_port", port as i32)?;
+    #[test]
+    fn batches_span_zones_without_by_zone() {
+        let zones = [Some("aw"), Some("af"), Some("aw"), Some("af")];
 
-        Ok(new_object)
+        assert_eq!(
+            batch_indices(&zones, Some(3), false),
+            vec![vec![0, 1, 2], vec![3]]
+        );
+    }
+
+    #[test]
+    fn multi_attribute_changes_leaves_managed_values_to_the_relations() {
+        let values = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| serde_json::Value::from(*value))
+                .collect::<Vec<_>>()
+        };
+        let managed = values(&["web.sg", "web-lb"]);
+
+        // On a first apply neither the service group nor the loadbalancer exists yet
+        let (remove, add) =
+            multi_attribute_changes(&[], &values(&["infra.sg", "web.sg"]), &managed, false);
+        assert!(remove.is_empty());
+        assert_eq!(add, values(&["infra.sg"]));
+
+        let (remove, add) = multi_attribute_changes(
+            &values(&["web.sg", "web-lb", "old.sg"]),
+            &values(&["infra.sg"]),
+            &managed,
+            false,
+        );
+        assert_eq!(remove, values(&["old.sg"]));
+        assert_eq!(add, values(&["infra.sg"]));
+    }
+
+    #[test]
+    fn network_references_include_external_peer_networks() {
+        let plan = serde_yml::from_str::<ServicePlan>(
+            r#"
+services:
+  web:
+    instances:
+      af:
+        replicas: 1
+        project_network: "prod.web.ig.local"
+    vm: {}
+    firewall:
+      external:
+        - name: "web-office.sg"
+          direction: inbound
+          ports: [tcp443]
+          networks:
+            - type: public_network
+              name: "office"
+"#,
+        )
+        .unwrap();
+
+        let references = ServicePlanProcessor::new(plan)
+            .network_references()
+            .unwrap();
+
+        assert_eq!(
+            references
+                .iter()
+                .map(|(location, network)| (location.as_str(), network.name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "services.web.instances.af.project_network",
+                    "prod.web.ig.local"
+                ),
+                ("services.web.firewall.external[0].networks[0]", "office"),
+            ]
+        );
     }
 }
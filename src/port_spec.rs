@@ -0,0 +1,373 @@
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    /// Shorthand for tcp and udp
+    Both,
+}
+
+impl PortProtocol {
+    const ALL: [(&'static str, PortProtocol); 4] = [
+        ("tcp", PortProtocol::Tcp),
+        ("udp", PortProtocol::Udp),
+        ("icmp", PortProtocol::Icmp),
+        ("both", PortProtocol::Both),
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PortProtocol::Tcp => "tcp",
+            PortProtocol::Udp => "udp",
+            PortProtocol::Icmp => "icmp",
+            PortProtocol::Both => "both",
+        }
+    }
+
+    /// The protocols as they are stored in Serveradmin
+    pub fn expand(&self) -> Vec<PortProtocol> {
+        match self {
+            PortProtocol::Both => vec![PortProtocol::Tcp, PortProtocol::Udp],
+            protocol => vec![*protocol],
+        }
+    }
+}
+
+/// A firewall port like `tcp443`, `udp8000-8100`, `both53` or `icmp`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortSpec {
+    pub protocol: PortProtocol,
+    /// The inclusive port range, None for icmp
+    pub ports: Option<(u16, u16)>,
+}
+
+impl PortSpec {
+    /// The values of the `protocol_ports_*` attributes
+    pub fn attribute_values(&self) -> Vec<String> {
+        self.protocol
+            .expand()
+            .into_iter()
+            .map(|protocol| {
+                PortSpec {
+                    protocol,
+                    ports: self.ports,
+                }
+                .to_string()
+            })
+            .collect()
+    }
+
+    /// The ports, which are part of both specs
+    pub fn intersection(&self, other: &PortSpec) -> Option<PortSpec> {
+        let other_protocols = other.protocol.expand();
+        let protocols = self
+            .protocol
+            .expand()
+            .into_iter()
+            .filter(|protocol| other_protocols.contains(protocol))
+            .collect::<Vec<_>>();
+        let protocol = match protocols.as_slice() {
+            [] => return None,
+            [protocol] => *protocol,
+            _ => PortProtocol::Both,
+        };
+        let ports = match (self.ports, other.ports) {
+            (Some((start, end)), Some((other_start, other_end))) => {
+                let ports = (start.max(other_start), end.min(other_end));
+
+                if ports.0 > ports.1 {
+                    return None;
+                }
+
+                Some(ports)
+            }
+            (None, None) => None,
+            _ => return None,
+        };
+
+        Some(PortSpec { protocol, ports })
+    }
+
+    /// The ports of this spec, which are not part of the other one
+    pub fn subtract(&self, other: &PortSpec) -> Vec<PortSpec> {
+        if other.covers(self) {
+            return Vec::new();
+        }
+
+        let other_protocols = other.protocol.expand();
+        let mut remaining: Vec<PortSpec> = Vec::new();
+        for protocol in self.protocol.expand() {
+            let ranges = match (self.ports, other.ports) {
+                _ if !other_protocols.contains(&protocol) => vec![self.ports],
+                (Some((start, end)), Some((other_start, other_end))) => {
+                    let mut ranges = Vec::new();
+                    if start < other_start {
+                        ranges.push(Some((start, end.min(other_start - 1))));
+                    }
+                    if other_end < end {
+                        ranges.push(Some((start.max(other_end + 1), end)));
+                    }
+
+                    ranges
+                }
+                (None, None) => Vec::new(),
+                _ => vec![self.ports],
+            };
+
+            for ports in ranges {
+                // The same ports left for tcp and udp are merged back into both
+                match remaining.iter_mut().find(|spec| spec.ports == ports) {
+                    Some(spec) if spec.protocol == PortProtocol::Tcp => {
+                        spec.protocol = PortProtocol::Both
+                    }
+                    _ => remaining.push(PortSpec { protocol, ports }),
+                }
+            }
+        }
+
+        remaining
+    }
+
+    /// Whether every port of the other spec is also part of this spec
+    pub fn covers(&self, other: &PortSpec) -> bool {
+        let protocols = self.protocol.expand();
+        if !other
+            .protocol
+            .expand()
+            .iter()
+            .all(|protocol| protocols.contains(protocol))
+        {
+            return false;
+        }
+
+        match (self.ports, other.ports) {
+            (Some((start, end)), Some((other_start, other_end))) => {
+                start <= other_start && other_end <= end
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether both specs share at least one port
+    pub fn overlaps(&self, other: &PortSpec) -> bool {
+        let protocols = self.protocol.expand();
+        if !other
+            .protocol
+            .expand()
+            .iter()
+            .any(|protocol| protocols.contains(protocol))
+        {
+            return false;
+        }
+
+        match (self.ports, other.ports) {
+            (Some((start, end)), Some((other_start, other_end))) => {
+                start <= other_end && other_start <= end
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for PortSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let Some((name, protocol)) = PortProtocol::ALL
+            .into_iter()
+            .find(|(name, _)| spec.starts_with(name))
+        else {
+            return Err(format!(
+                "Unknown protocol in port {spec:?}, expected tcp, udp, icmp or both"
+            ));
+        };
+        let range = &spec[name.len()..];
+
+        if protocol == PortProtocol::Icmp {
+            if !range.is_empty() {
+                return Err(format!("icmp does not have ports, but got {spec:?}"));
+            }
+
+            return Ok(Self {
+                protocol,
+                ports: None,
+            });
+        }
+
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .ok()
+                .filter(|port| *port > 0)
+                .ok_or(format!("Invalid port {port:?} in {spec:?}"))
+        };
+        let ports = match range.split_once('-') {
+            Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+            None => (parse_port(range)?, parse_port(range)?),
+        };
+
+        if ports.0 > ports.1 {
+            return Err(format!("The port range of {spec:?} is reversed"));
+        }
+
+        Ok(Self {
+            protocol,
+            ports: Some(ports),
+        })
+    }
+}
+
+impl TryFrom<String> for PortSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        spec.parse()
+    }
+}
+
+impl From<PortSpec> for String {
+    fn from(spec: PortSpec) -> Self {
+        spec.to_string()
+    }
+}
+
+impl std::fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.protocol.name())?;
+
+        match self.ports {
+            Some((start, end)) if start == end => write!(f, "{start}"),
+            Some((start, end)) => write!(f, "{start}-{end}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses port specs for the tests of this and other modules
+#[cfg(test)]
+pub fn ports(specs: &[&str]) -> Vec<PortSpec> {
+    specs.iter().map(|spec| spec.parse().unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(spec: &str) -> PortSpec {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn from_str_parses_single_ports_and_ranges() {
+        assert_eq!(
+            port("tcp443"),
+            PortSpec {
+                protocol: PortProtocol::Tcp,
+                ports: Some((443, 443)),
+            }
+        );
+        assert_eq!(
+            port("both8000-8100"),
+            PortSpec {
+                protocol: PortProtocol::Both,
+                ports: Some((8000, 8100)),
+            }
+        );
+        assert_eq!(
+            port("icmp"),
+            PortSpec {
+                protocol: PortProtocol::Icmp,
+                ports: None,
+            }
+        );
+    }
+
+    #[test]
+    fn from_str_rejects_invalid_specs() {
+        for spec in [
+            "sctp80", "tcp", "tcp0", "tcp70000", "tcp90-80", "icmp8", "udpabc",
+        ] {
+            assert!(spec.parse::<PortSpec>().is_err(), "{spec} was accepted");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for spec in ["tcp443", "udp8000-8100", "both53", "icmp"] {
+            assert_eq!(port(spec).to_string(), spec);
+        }
+    }
+
+    #[test]
+    fn attribute_values_expand_both() {
+        assert_eq!(port("both53").attribute_values(), vec!["tcp53", "udp53"]);
+        assert_eq!(port("tcp80-90").attribute_values(), vec!["tcp80-90"]);
+    }
+
+    #[test]
+    fn covers_needs_every_protocol_and_port() {
+        assert!(port("tcp1-1000").covers(&port("tcp80")));
+        assert!(port("both53").covers(&port("udp53")));
+        assert!(!port("tcp53").covers(&port("both53")));
+        assert!(!port("tcp80-90").covers(&port("tcp85-95")));
+        assert!(port("icmp").covers(&port("icmp")));
+        assert!(!port("tcp80").covers(&port("icmp")));
+    }
+
+    #[test]
+    fn overlaps_needs_a_shared_protocol_and_port() {
+        assert!(port("tcp80-90").overlaps(&port("tcp90-100")));
+        assert!(port("both53").overlaps(&port("udp53")));
+        assert!(!port("tcp80-90").overlaps(&port("tcp91-100")));
+        assert!(!port("tcp53").overlaps(&port("udp53")));
+        assert!(!port("icmp").overlaps(&port("tcp80")));
+    }
+
+    #[test]
+    fn intersection_narrows_protocols_and_ports() {
+        assert_eq!(
+            port("both80-90").intersection(&port("tcp85-100")),
+            Some(port("tcp85-90"))
+        );
+        assert_eq!(
+            port("both53").intersection(&port("both50-60")),
+            Some(port("both53"))
+        );
+        assert_eq!(port("tcp80").intersection(&port("udp80")), None);
+        assert_eq!(port("tcp80").intersection(&port("tcp81")), None);
+        assert_eq!(port("icmp").intersection(&port("icmp")), Some(port("icmp")));
+    }
+
+    #[test]
+    fn subtract_cuts_ranges() {
+        assert_eq!(
+            port("tcp8000-8100").subtract(&port("tcp8000-8050")),
+            vec![port("tcp8051-8100")]
+        );
+        assert_eq!(
+            port("tcp1-100").subtract(&port("tcp40-60")),
+            vec![port("tcp1-39"), port("tcp61-100")]
+        );
+        assert_eq!(port("tcp80").subtract(&port("tcp1-1000")), vec![]);
+        assert_eq!(port("tcp80").subtract(&port("udp80")), vec![port("tcp80")]);
+        assert_eq!(port("icmp").subtract(&port("icmp")), vec![]);
+    }
+
+    #[test]
+    fn subtract_keeps_both_protocols_together() {
+        assert_eq!(
+            port("both50-60").subtract(&port("both55-60")),
+            vec![port("both50-54")]
+        );
+        assert_eq!(port("both53").subtract(&port("tcp53")), vec![port("udp53")]);
+        assert_eq!(
+            port("both50-60").subtract(&port("udp55-65")),
+            vec![port("tcp50-60"), port("udp50-54")]
+        );
+    }
+}
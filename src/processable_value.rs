@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Formatter;
 
 use serde::de::{DeserializeSeed, EnumAccess, SeqAccess, VariantAccess, Visitor};
@@ -7,39 +7,134 @@ use serde::{Deserialize, Deserializer};
 #[derive(Clone, Debug, serde::Serialize)]
 pub struct ProcessableValue {
     pub is_template: bool,
+    /// Multi-value attributes only get values added, but never removed
+    pub is_append: bool,
     pub content: Option<serde_json::Value>,
     pub contents: Vec<Self>,
+    /// Resolved from Serveradmin while planning
+    pub query: Option<ValueQuery>,
+}
+
+/// A lookup of attribute values in Serveradmin, given as `!query`
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValueQuery {
+    pub filters: HashMap<String, ProcessableValue>,
+    /// The attribute of the found objects, which makes up the value
+    #[serde(default = "ValueQuery::default_restrict")]
+    pub restrict: String,
+    #[serde(default)]
+    pub expect: QueryExpectation,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryExpectation {
+    /// Exactly one object has to match, the value is its attribute
+    #[default]
+    One,
+    /// Any number of objects may match, the value is the list of their attributes
+    Many,
+}
+
+impl ValueQuery {
+    fn default_restrict() -> String {
+        "hostname".to_string()
+    }
+
+    /// The filters with their templates rendered, ordered to identify the query
+    pub fn render_filters(
+        &self,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        self.filters
+            .iter()
+            .map(|(attribute, value)| match value.render(variables)? {
+                serde_json::Value::String(value) => Ok((attribute.clone(), value)),
+                _ => Err(anyhow::anyhow!(
+                    "The query filter {attribute} has to be a string"
+                )),
+            })
+            .collect()
+    }
 }
 
 impl ProcessableValue {
     pub fn fixed(content: serde_json::Value) -> Self {
         Self {
             is_template: false,
+            is_append: false,
             content: Some(content),
             contents: Vec::new(),
+            query: None,
         }
     }
 
     pub fn template(content: serde_json::Value) -> Self {
         Self {
             is_template: true,
+            is_append: false,
             content: Some(content),
             contents: Vec::new(),
+            query: None,
         }
     }
 
     pub fn sequence(contents: Vec<Self>) -> Self {
         Self {
             is_template: false,
+            is_append: false,
             content: None,
             contents,
+            query: None,
         }
     }
 
+    pub fn query(query: ValueQuery) -> Self {
+        Self {
+            is_template: false,
+            is_append: false,
+            content: None,
+            contents: Vec::new(),
+            query: Some(query),
+        }
+    }
+
+    pub fn appending(mut self) -> Self {
+        self.is_append = true;
+
+        self
+    }
+
+    /// The queries of this value and its nested values
+    pub fn queries(&self) -> Vec<&ValueQuery> {
+        self.query
+            .iter()
+            .chain(self.contents.iter().flat_map(|value| value.queries()))
+            .collect()
+    }
+
     pub fn render(
         &self,
         variables: &HashMap<String, &dyn strfmt::DisplayStr>,
     ) -> anyhow::Result<serde_json::Value> {
+        self.render_with(variables, &|_| {
+            Err(anyhow::anyhow!(
+                "!query values are only supported in object attributes"
+            ))
+        })
+    }
+
+    /// Renders the value, taking the results of queries from `query_result`
+    pub fn render_with(
+        &self,
+        variables: &HashMap<String, &dyn strfmt::DisplayStr>,
+        query_result: &dyn Fn(&ValueQuery) -> anyhow::Result<serde_json::Value>,
+    ) -> anyhow::Result<serde_json::Value> {
+        if let Some(query) = &self.query {
+            return query_result(query);
+        }
+
         if let Some(serde_json::Value::String(str)) = &self.content {
             if self.is_template {
                 return Ok(serde_json::Value::String(strfmt::strfmt(str, variables)?));
@@ -50,7 +145,7 @@ impl ProcessableValue {
             return Ok(serde_json::Value::Array(
                 self.contents
                     .iter()
-                    .map(|value| value.render(variables))
+                    .map(|value| value.render_with(variables, query_result))
                     .collect::<anyhow::Result<Vec<_>>>()?,
             ));
         }
@@ -59,7 +154,7 @@ impl ProcessableValue {
     }
 }
 
-struct ProcessableValueVisitor;
+pub struct ProcessableValueVisitor;
 
 struct TagStringVisitor;
 
@@ -128,6 +223,27 @@ impl<'de> Visitor<'de> for ProcessableValueVisitor {
         Ok(ProcessableValue::fixed(serde_json::Value::Bool(v)))
     }
 
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(ProcessableValue::fixed(serde_json::Value::from(v)))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(ProcessableValue::fixed(serde_json::Value::from(v)))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(ProcessableValue::fixed(serde_json::Value::from(v)))
+    }
+
     fn visit_none<E>(self) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
@@ -160,11 +276,14 @@ impl<'de> Visitor<'de> for ProcessableValueVisitor {
         A: EnumAccess<'de>,
     {
         let (tag, contents) = data.variant_seed(TagStringVisitor)?;
-        let value = contents.newtype_variant()?;
 
         let value = match tag.as_str() {
-            "static" => ProcessableValue::fixed(value),
-            "template" => ProcessableValue::template(value),
+            "static" => ProcessableValue::fixed(contents.newtype_variant()?),
+            "template" => ProcessableValue::template(contents.newtype_variant()?),
+            "query" => ProcessableValue::query(contents.newtype_variant()?),
+            "append" => contents
+                .newtype_variant_seed(ProcessableValueVisitor)?
+                .appending(),
             tag => return Err(serde::de::Error::custom(format!("Unknown tag {tag}"))),
         };
 